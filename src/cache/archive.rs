@@ -0,0 +1,405 @@
+use std::{
+    collections::{BTreeSet, HashSet},
+    fs::{self, File},
+    io::{BufReader, BufWriter, Read, Write},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, bail};
+use blake3::Hash;
+use chrono::{DateTime, Utc};
+use smol_str::{SmolStr, ToSmolStr};
+use tracing::{debug, warn};
+
+use crate::cache::{
+    command::{COMMAND_DIR, COMMAND_FILE_NAME, CachedCommand},
+    dictionary::{DICTIONARY_DIR, DictionaryReference},
+    file::{COMPRESSED_FILE_NAME, CachedFile, DATA_FILE_NAME},
+    folder::{CacheFolder, FILE_FOLDER_NAME},
+    pack::PackIndex,
+};
+
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+
+/// Selects the commands to export, without project and commands everything is selected.
+#[derive(Debug, Clone, Default)]
+pub struct ExportFilter {
+    pub project: Option<SmolStr>,
+    pub commands: Vec<SmolStr>,
+    pub since: Option<DateTime<Utc>>,
+}
+
+impl ExportFilter {
+    fn matches(&self, command: &CachedCommand) -> bool {
+        let selected = (self.project.is_none() && self.commands.is_empty())
+            || (self.project.is_some() && command.project == self.project)
+            || self.commands.contains(&command.hash);
+        let recent = self
+            .since
+            .is_none_or(|since| command.last_accessed >= since);
+        selected && recent
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct ExportSummary {
+    pub commands: usize,
+    pub blobs: usize,
+}
+
+#[derive(Debug, Default)]
+pub struct ImportSummary {
+    pub commands: usize,
+    pub skipped_commands: usize,
+    pub blobs: usize,
+}
+
+fn append_bytes<W: Write>(
+    builder: &mut tar::Builder<W>,
+    path: impl AsRef<Path>,
+    bytes: &[u8],
+) -> anyhow::Result<()> {
+    let mut header = tar::Header::new_gnu();
+    header.set_size(bytes.len() as u64);
+    header.set_mode(0o644);
+    header.set_mtime(Utc::now().timestamp() as u64);
+    builder.append_data(&mut header, path, bytes)?;
+    Ok(())
+}
+
+/// Writes the selected command records with every blob they reference into a tar archive,
+/// compressed with zstd if `output` ends with `.zst`.
+pub fn export(
+    cache_root: &Path,
+    filter: &ExportFilter,
+    output: &Path,
+) -> anyhow::Result<ExportSummary> {
+    let cache_folder = CacheFolder::new(cache_root);
+    let commands = cache_folder
+        .list_commands()?
+        .into_iter()
+        .filter(|command| filter.matches(command))
+        .collect::<Vec<_>>();
+
+    let projects = commands
+        .iter()
+        .filter_map(|c| c.project.clone())
+        .collect::<BTreeSet<_>>();
+    let dictionaries = projects
+        .iter()
+        .map(|project| {
+            cache_root
+                .join(DICTIONARY_DIR)
+                .join(format!("{}.json", project))
+        })
+        .filter(|path| path.exists())
+        .map(|path| {
+            let reference: DictionaryReference = serde_json::from_reader(File::open(&path)?)
+                .with_context(|| format!("Could not parse {:?}", path))?;
+            Ok((path, reference))
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+
+    let mut pending = commands
+        .iter()
+        .flat_map(|c| c.referenced_blobs())
+        .chain(dictionaries.iter().map(|(_, r)| r.hash.clone()))
+        .collect::<Vec<_>>();
+    let mut seen = HashSet::new();
+    let mut blobs = Vec::new();
+    while let Some(hash) = pending.pop() {
+        if !seen.insert(hash.clone()) {
+            continue;
+        }
+        let file = CachedFile::open(cache_root, &Hash::from_hex(hash.as_bytes())?)
+            .with_context(|| format!("Could not open blob {} for export", hash))?;
+        pending.extend(file.referenced_blobs());
+        blobs.push((hash, file));
+    }
+
+    let file = BufWriter::new(
+        File::create(output).with_context(|| format!("Could not create {:?}", output))?,
+    );
+    let writer: Box<dyn Write> = if output.extension().is_some_and(|e| e == "zst") {
+        Box::new(zstd::Encoder::new(file, 3)?.auto_finish())
+    } else {
+        Box::new(file)
+    };
+    let mut builder = tar::Builder::new(writer);
+    for (hash, file) in &blobs {
+        let dir = Path::new(FILE_FOLDER_NAME).join(hash.as_str());
+        append_bytes(
+            &mut builder,
+            dir.join(DATA_FILE_NAME),
+            serde_json::to_string(&file.data)?.as_bytes(),
+        )?;
+        if file.data.chunks.is_none() {
+            let (reader, length) = file.stored_reader()?;
+            let mut header = tar::Header::new_gnu();
+            header.set_size(length);
+            header.set_mode(0o644);
+            header.set_mtime(file.data.created.timestamp() as u64);
+            builder.append_data(&mut header, dir.join(COMPRESSED_FILE_NAME), reader)?;
+        }
+    }
+    for command in &commands {
+        let path = Path::new(COMMAND_DIR)
+            .join(command.hash.as_str())
+            .join(COMMAND_FILE_NAME);
+        // the stored bytes, an encrypted record must stay sealed in the archive
+        let stored = cache_root.join(&path);
+        let bytes = fs::read(&stored).with_context(|| format!("Could not read {:?}", stored))?;
+        append_bytes(&mut builder, path, &bytes)?;
+    }
+    for (path, reference) in &dictionaries {
+        let name = path.file_name().expect("dictionary reference file name");
+        append_bytes(
+            &mut builder,
+            Path::new(DICTIONARY_DIR).join(name),
+            serde_json::to_string(reference)?.as_bytes(),
+        )?;
+    }
+    builder.into_inner()?.flush()?;
+
+    Ok(ExportSummary {
+        commands: commands.len(),
+        blobs: blobs.len(),
+    })
+}
+
+fn unpack(archive: &Path, target: &Path) -> anyhow::Result<()> {
+    let mut magic = [0u8; 4];
+    let is_zstd = File::open(archive)
+        .with_context(|| format!("Could not open {:?}", archive))?
+        .read_exact(&mut magic)
+        .is_ok()
+        && magic == ZSTD_MAGIC;
+    let file = BufReader::new(File::open(archive)?);
+    let reader: Box<dyn Read> = if is_zstd {
+        Box::new(zstd::Decoder::new(file)?)
+    } else {
+        Box::new(file)
+    };
+    tar::Archive::new(reader)
+        .unpack(target)
+        .with_context(|| format!("Archive {:?} is corrupt", archive))
+}
+
+fn staged_blob_hashes(staging: &Path) -> anyhow::Result<Vec<SmolStr>> {
+    let files_dir = staging.join(FILE_FOLDER_NAME);
+    if !files_dir.exists() {
+        return Ok(vec![]);
+    }
+    fs::read_dir(&files_dir)?
+        .map(|entry| Ok(entry?.file_name().to_string_lossy().to_smolstr()))
+        .collect()
+}
+
+/// Checks that every staged blob decompresses to its hash and every command only references
+/// blobs that are either staged or already cached.
+async fn validate(cache_root: &Path, staging: &Path) -> anyhow::Result<Vec<CachedCommand>> {
+    let mut errors = Vec::new();
+    let staged_blobs = staged_blob_hashes(staging)?;
+    for hash in &staged_blobs {
+        let expected = Hash::from_hex(hash.as_bytes())
+            .with_context(|| format!("Invalid blob name {} in archive", hash))?;
+        let actual = match CachedFile::open(staging, &expected) {
+            Ok(file) => file.hash_content().await,
+            Err(e) => Err(e),
+        };
+        match actual {
+            Ok(actual) if actual == expected => debug!("Verified blob {}", hash),
+            Ok(actual) => errors.push(format!("blob {} hashes to {}", hash, actual)),
+            Err(e) => errors.push(format!("blob {} is unreadable: {:#}", hash, e)),
+        }
+    }
+
+    let commands = CacheFolder::new(staging)
+        .list_commands()
+        .context("Archive contains an unreadable command record")?;
+    for command in &commands {
+        for hash in command.referenced_blobs() {
+            let hash = Hash::from_hex(hash.as_bytes())?;
+            if !CachedFile::is_stored(staging, &hash)? && !CachedFile::is_stored(cache_root, &hash)?
+            {
+                errors.push(format!(
+                    "command {} references missing blob {}",
+                    command.hash, hash
+                ));
+            }
+        }
+    }
+    if !errors.is_empty() {
+        bail!(
+            "Archive is corrupt, nothing was imported:\n  {}",
+            errors.join("\n  ")
+        );
+    }
+    Ok(commands)
+}
+
+fn is_newer_dictionary(existing: &Path, imported: &DictionaryReference) -> anyhow::Result<bool> {
+    if !existing.exists() {
+        return Ok(true);
+    }
+    let existing: DictionaryReference = serde_json::from_reader(File::open(existing)?)?;
+    Ok(existing.created < imported.created)
+}
+
+async fn merge(
+    cache_root: &Path,
+    staging: &Path,
+    commands: Vec<CachedCommand>,
+) -> anyhow::Result<ImportSummary> {
+    let mut summary = ImportSummary::default();
+    let files_dir = cache_root.join(FILE_FOLDER_NAME);
+    fs::create_dir_all(&files_dir)?;
+    for hash in staged_blob_hashes(staging)? {
+        if CachedFile::is_stored(cache_root, &Hash::from_hex(hash.as_bytes())?)? {
+            continue;
+        }
+        fs::rename(
+            staging.join(FILE_FOLDER_NAME).join(hash.as_str()),
+            files_dir.join(hash.as_str()),
+        )?;
+        summary.blobs += 1;
+    }
+
+    let cache_folder = CacheFolder::new(cache_root);
+    for command in commands {
+        let hash = Hash::from_hex(command.hash.as_bytes())?;
+        if cache_folder.has_cached_command(&hash) {
+            match cache_folder.get_cashed_command(&hash) {
+                Ok(existing) if existing.created >= command.created => {
+                    debug!("Keeping newer cached command {}", hash);
+                    summary.skipped_commands += 1;
+                    continue;
+                }
+                Ok(_) => {}
+                Err(e) => warn!("Replacing unreadable command {}: {:#}", hash, e),
+            }
+        }
+        command.store_in_cache(cache_root).await?;
+        summary.commands += 1;
+    }
+
+    let dictionary_dir = staging.join(DICTIONARY_DIR);
+    if dictionary_dir.exists() {
+        fs::create_dir_all(cache_root.join(DICTIONARY_DIR))?;
+        for entry in fs::read_dir(&dictionary_dir)? {
+            let path = entry?.path();
+            let reference: DictionaryReference = serde_json::from_reader(File::open(&path)?)
+                .with_context(|| format!("Archive contains unreadable dictionary {:?}", path))?;
+            let target = cache_root
+                .join(DICTIONARY_DIR)
+                .join(path.file_name().expect("dictionary reference file name"));
+            if is_newer_dictionary(&target, &reference)? {
+                fs::copy(&path, &target)?;
+            }
+        }
+    }
+    Ok(summary)
+}
+
+/// Imports an archive written by [`export`], the archive is validated completely before
+/// anything is merged into the cache. Existing commands that are newer are kept.
+pub async fn import(cache_root: &Path, archive: &Path) -> anyhow::Result<ImportSummary> {
+    let staging: PathBuf = cache_root.join(format!(".import-{}", std::process::id()));
+    if staging.exists() {
+        fs::remove_dir_all(&staging)?;
+    }
+    fs::create_dir_all(&staging)?;
+    let result = async {
+        let (archive_path, staging_path) = (archive.to_owned(), staging.clone());
+        tokio::task::spawn_blocking(move || unpack(&archive_path, &staging_path)).await??;
+        let commands = validate(cache_root, &staging).await?;
+        merge(cache_root, &staging, commands).await
+    }
+    .await;
+    PackIndex::invalidate(&staging);
+    fs::remove_dir_all(&staging)?;
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cache::test_util::{command, store_blob};
+
+    #[tokio::test]
+    async fn exported_commands_are_imported_with_their_blobs() {
+        let source = tempfile::tempdir().unwrap();
+        let log = store_blob(source.path(), b"hello\n").await;
+        command("a", log)
+            .store_in_cache(source.path())
+            .await
+            .unwrap();
+        let archive = source.path().join("export.tar.zst");
+        let exported = export(source.path(), &ExportFilter::default(), &archive).unwrap();
+        assert_eq!((exported.commands, exported.blobs), (1, 1));
+
+        let target = tempfile::tempdir().unwrap();
+        let imported = import(target.path(), &archive).await.unwrap();
+        assert_eq!((imported.commands, imported.blobs), (1, 1));
+        let file = CachedFile::open(target.path(), &log).unwrap();
+        assert_eq!(file.hash_content().await.unwrap(), log);
+        assert_eq!(
+            CacheFolder::new(target.path())
+                .list_commands()
+                .unwrap()
+                .len(),
+            1
+        );
+
+        let again = import(target.path(), &archive).await.unwrap();
+        assert_eq!((again.commands, again.skipped_commands), (0, 1));
+    }
+
+    #[tokio::test]
+    async fn archives_with_missing_blobs_are_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        let archive = dir.path().join("broken.tar");
+        let record = command("a", blake3::hash(b"missing"));
+        let mut builder = tar::Builder::new(File::create(&archive).unwrap());
+        let path = Path::new(COMMAND_DIR)
+            .join(record.hash.as_str())
+            .join(COMMAND_FILE_NAME);
+        append_bytes(&mut builder, path, &serde_json::to_vec(&record).unwrap()).unwrap();
+        builder.into_inner().unwrap().flush().unwrap();
+
+        let target = tempfile::tempdir().unwrap();
+        let error = import(target.path(), &archive).await.unwrap_err();
+        assert!(
+            error.to_string().contains("nothing was imported"),
+            "{:#}",
+            error
+        );
+        assert!(
+            CacheFolder::new(target.path())
+                .list_commands()
+                .unwrap()
+                .is_empty()
+        );
+    }
+
+    #[tokio::test]
+    async fn since_excludes_commands_not_used_recently() {
+        let source = tempfile::tempdir().unwrap();
+        let log = store_blob(source.path(), b"hello\n").await;
+        command("recent", log)
+            .store_in_cache(source.path())
+            .await
+            .unwrap();
+        let mut old = command("old", log);
+        old.last_accessed = Utc::now() - chrono::Duration::days(10);
+        old.store_in_cache(source.path()).await.unwrap();
+
+        let filter = ExportFilter {
+            since: Some(Utc::now() - chrono::Duration::days(3)),
+            ..Default::default()
+        };
+        let archive = source.path().join("export.tar");
+        let exported = export(source.path(), &filter, &archive).unwrap();
+        assert_eq!(exported.commands, 1);
+    }
+}
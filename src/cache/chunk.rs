@@ -0,0 +1,181 @@
+use std::{fs::File, path::Path};
+
+use anyhow::{Context, bail};
+use blake3::Hash;
+use fastcdc::v2020::{
+    AVERAGE_MAX, AVERAGE_MIN, MAXIMUM_MAX, MAXIMUM_MIN, MINIMUM_MAX, MINIMUM_MIN, StreamCDC,
+};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+/// Files of at least `min_file_size` bytes are split into content defined chunks,
+/// so different versions of a large output share the chunks that did not change.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(default, deny_unknown_fields)]
+pub struct ChunkingConfig {
+    pub min_file_size: u64,
+    pub min_chunk_size: u32,
+    pub avg_chunk_size: u32,
+    pub max_chunk_size: u32,
+}
+
+impl Default for ChunkingConfig {
+    fn default() -> Self {
+        Self {
+            min_file_size: 64 * 1024 * 1024,
+            min_chunk_size: 256 * 1024,
+            avg_chunk_size: 1024 * 1024,
+            max_chunk_size: 4 * 1024 * 1024,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ChunkRange {
+    pub hash: Hash,
+    pub offset: u64,
+    pub length: usize,
+}
+
+impl ChunkingConfig {
+    pub fn applies_to(&self, file_size: u64) -> bool {
+        file_size >= self.min_file_size
+    }
+
+    /// Sizes FastCDC would reject with a panic. Smaller files than a chunk are never chunked,
+    /// otherwise a file could be stored as a single chunk with its own hash.
+    pub fn problems(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        let bounds = [
+            (
+                "min_chunk_size",
+                self.min_chunk_size,
+                MINIMUM_MIN,
+                MINIMUM_MAX,
+            ),
+            (
+                "avg_chunk_size",
+                self.avg_chunk_size,
+                AVERAGE_MIN,
+                AVERAGE_MAX,
+            ),
+            (
+                "max_chunk_size",
+                self.max_chunk_size,
+                MAXIMUM_MIN,
+                MAXIMUM_MAX,
+            ),
+        ];
+        for (field, value, min, max) in bounds {
+            if !(min..=max).contains(&value) {
+                problems.push(format!(
+                    "{} must be between {} and {}, got {}",
+                    field, min, max, value
+                ));
+            }
+        }
+        if !(self.min_chunk_size <= self.avg_chunk_size
+            && self.avg_chunk_size <= self.max_chunk_size)
+        {
+            problems.push(format!(
+                "chunk sizes need min <= avg <= max, got {} / {} / {}",
+                self.min_chunk_size, self.avg_chunk_size, self.max_chunk_size
+            ));
+        }
+        if self.min_file_size <= u64::from(self.max_chunk_size) {
+            problems.push(format!(
+                "min_file_size must be larger than max_chunk_size, got {} / {}",
+                self.min_file_size, self.max_chunk_size
+            ));
+        }
+        problems
+    }
+
+    /// Splits the file with FastCDC, only the chunk boundaries and hashes are kept in memory.
+    pub fn chunk_file(&self, path: &Path) -> anyhow::Result<Vec<ChunkRange>> {
+        let problems = self.problems();
+        if !problems.is_empty() {
+            bail!("Invalid chunking configuration: {}", problems.join(", "));
+        }
+        let file = File::open(path).with_context(|| format!("Could not open {:?}", path))?;
+        StreamCDC::new(
+            file,
+            self.min_chunk_size,
+            self.avg_chunk_size,
+            self.max_chunk_size,
+        )
+        .map(|chunk| {
+            let chunk = chunk.with_context(|| format!("Could not chunk {:?}", path))?;
+            Ok(ChunkRange {
+                hash: blake3::hash(&chunk.data),
+                offset: chunk.offset,
+                length: chunk.length,
+            })
+        })
+        .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use super::*;
+
+    fn small() -> ChunkingConfig {
+        ChunkingConfig {
+            min_file_size: 64 * 1024,
+            min_chunk_size: 1024,
+            avg_chunk_size: 4096,
+            max_chunk_size: 16 * 1024,
+        }
+    }
+
+    #[test]
+    fn default_sizes_are_valid() {
+        assert!(ChunkingConfig::default().problems().is_empty());
+        assert!(small().problems().is_empty());
+    }
+
+    #[test]
+    fn sizes_outside_of_fastcdc_bounds_are_rejected() {
+        let config = ChunkingConfig {
+            min_chunk_size: 16,
+            max_chunk_size: 32 * 1024 * 1024,
+            ..small()
+        };
+        let problems = config.problems();
+        assert!(problems.iter().any(|p| p.starts_with("min_chunk_size")));
+        assert!(problems.iter().any(|p| p.starts_with("max_chunk_size")));
+        assert!(config.chunk_file(Path::new("/nonexistent")).is_err());
+    }
+
+    #[test]
+    fn files_must_be_larger_than_a_chunk() {
+        let config = ChunkingConfig {
+            min_file_size: 16 * 1024,
+            ..small()
+        };
+        assert_eq!(config.problems().len(), 1);
+    }
+
+    #[test]
+    fn chunks_cover_the_file() {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        let data: Vec<u8> = (0..256 * 1024u32)
+            .map(|i| (i.wrapping_mul(2_654_435_761) >> 13) as u8)
+            .collect();
+        file.write_all(&data).unwrap();
+
+        let chunks = small().chunk_file(file.path()).unwrap();
+        assert!(chunks.len() > 1);
+        let mut offset = 0;
+        for chunk in chunks {
+            assert_eq!(chunk.offset, offset as u64);
+            let bytes = &data[offset..offset + chunk.length];
+            assert_eq!(chunk.hash, blake3::hash(bytes));
+            offset += chunk.length;
+        }
+        assert_eq!(offset, data.len());
+    }
+}
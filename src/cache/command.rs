@@ -1,4 +1,4 @@
-use anyhow::Ok;
+use anyhow::{Context, Ok, bail};
 use blake3::Hash;
 use chrono::{DateTime, Utc};
 
@@ -13,16 +13,34 @@ use std::{
 use tokio::{fs::File, io::AsyncWriteExt};
 use tracing::debug;
 
-pub const COMMAND_DIR: &'static str = "commands";
-pub const COMMAND_FILE_NAME: &'static str = "command.json";
+use crate::{
+    cache::{
+        encryption::{SealedRecord, installed_key},
+        format::read_record,
+        signing::CommandSignature,
+    },
+    error::CacheError,
+};
+
+pub const COMMAND_DIR: &str = "commands";
+pub const COMMAND_FILE_NAME: &str = "command.json";
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct OutputFile {
     pub paths: Vec<SmolStr>,
     pub hash: SmolStr,
 }
+/// Layout of `command.json` with encryption, only the version stays readable.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct SealedCommand {
+    version: u32,
+    sealed: SealedRecord,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct CachedCommand {
+    #[serde(default)]
+    pub version: u32,
     pub command_line: SmolStr,
     pub env: BTreeMap<String, String>,
     pub hash: SmolStr,
@@ -30,13 +48,27 @@ pub struct CachedCommand {
     pub last_accessed: DateTime<Utc>,
     pub log: SmolStr,
     pub output_files: Vec<OutputFile>,
+    #[serde(default)]
+    pub project: Option<SmolStr>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub task: Option<SmolStr>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signature: Option<CommandSignature>,
 }
 
 impl CachedCommand {
+    /// Hashes of the log and all output files.
+    pub fn referenced_blobs(&self) -> Vec<SmolStr> {
+        std::iter::once(self.log.clone())
+            .chain(self.output_files.iter().map(|o| o.hash.clone()))
+            .collect()
+    }
+
     pub fn create_hash(
         commandline: &str,
         mut files: Vec<PathBuf>,
         filtered_env: &BTreeMap<String, String>,
+        dependency_outputs: &BTreeMap<SmolStr, Vec<SmolStr>>,
     ) -> anyhow::Result<Hash> {
         files.par_sort_by_key(|e| e.canonicalize().expect("full path")); //fixme
 
@@ -48,6 +80,13 @@ impl CachedCommand {
             hasher.update(v.as_bytes());
         }
 
+        for (project, outputs) in dependency_outputs {
+            hasher.update(project.as_bytes());
+            outputs
+                .iter()
+                .for_each(|hash| _ = hasher.update(hash.as_bytes()));
+        }
+
         let amount = files.len();
         let start = SystemTime::now();
         for file in files {
@@ -63,12 +102,42 @@ impl CachedCommand {
         Ok(result)
     }
 
+    /// Reads a `command.json`, opening it if it was sealed by [`Self::store_in_cache`].
+    pub fn load(path: &Path, hash: &str) -> anyhow::Result<Self> {
+        let record: serde_json::Value = read_record(path)?;
+        match record.get("sealed") {
+            Some(_) if installed_key().is_none() => {
+                bail!(CacheError::MissingKey { hash: hash.into() })
+            }
+            Some(sealed) => {
+                let sealed: SealedRecord = serde_json::from_value(sealed.clone())?;
+                let json = sealed
+                    .open(hash.as_bytes())
+                    .with_context(|| format!("Could not decrypt {:?}", path))?;
+                Ok(serde_json::from_slice(&json)?)
+            }
+            None => Ok(serde_json::from_value(record)?),
+        }
+    }
+
+    /// Stores the record as `command.json`, sealed with the command hash as associated data
+    /// if an encryption key is installed.
     pub async fn store_in_cache(self, cache_dir: &Path) -> anyhow::Result<()> {
-        let json = serde_json::to_string(&self)?;
-        let target_folder = cache_dir.join(COMMAND_DIR).join(self.hash.to_string());
+        let mut json = serde_json::to_string(&self)?;
+        if let Some(sealed) = SealedRecord::seal(json.as_bytes(), self.hash.as_bytes())? {
+            json = serde_json::to_string(&SealedCommand {
+                version: self.version,
+                sealed,
+            })?;
+        }
+        let target_folder = cache_dir.join(COMMAND_DIR).join(&self.hash);
         tokio::fs::create_dir_all(&target_folder).await?;
-        let mut file = File::create(target_folder.join(COMMAND_FILE_NAME)).await?;
+        // written next to it and renamed, so other commands never read a partial record
+        let temp_path = target_folder.join(format!("{}.tmp", COMMAND_FILE_NAME));
+        let mut file = File::create(&temp_path).await?;
         file.write_all(json.as_bytes()).await?;
+        file.flush().await?;
+        tokio::fs::rename(&temp_path, target_folder.join(COMMAND_FILE_NAME)).await?;
         Ok(())
     }
 }
@@ -0,0 +1,493 @@
+use std::{
+    borrow::Cow,
+    collections::BTreeMap,
+    fmt::Display,
+    num::NonZeroU32,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use anyhow::{Context, bail};
+use async_compression::{
+    Level,
+    tokio::bufread::{
+        BrotliDecoder, BrotliEncoder, XzDecoder, XzEncoder, ZstdDecoder, ZstdEncoder,
+    },
+    zstd::{CParameter, DParameter},
+};
+use glob::Pattern;
+use schemars::{JsonSchema, Schema, SchemaGenerator, json_schema};
+use serde::{Deserialize, Serialize};
+use smol_str::SmolStr;
+use tokio::io::{AsyncBufRead, AsyncWrite, copy, copy_buf};
+
+use crate::cache::{chunk::ChunkingConfig, dictionary::Dictionary};
+
+const XZ_MEM_LIMIT: u64 = 256 * 1024 * 1024;
+const ZSTD_LONG_WINDOW_LOG: u32 = 27;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Compression {
+    None,
+    Brotli,
+    Xz,
+    XzParallel,
+    Zstd,
+    ZstdParallel,
+    ZstdLong,
+    ZstdDictionary,
+}
+
+impl Compression {
+    const NAMES: [(&str, Compression); 8] = [
+        ("none", Compression::None),
+        ("brotli", Compression::Brotli),
+        ("xz", Compression::Xz),
+        ("xz-parallel", Compression::XzParallel),
+        ("zstd", Compression::Zstd),
+        ("zstd-parallel", Compression::ZstdParallel),
+        ("zstd-long", Compression::ZstdLong),
+        ("zstd-dictionary", Compression::ZstdDictionary),
+    ];
+
+    pub fn name(&self) -> &'static str {
+        Self::NAMES
+            .iter()
+            .find(|(_, c)| c == self)
+            .map(|(name, _)| *name)
+            .expect("every compression has a name")
+    }
+
+    fn level_range(&self) -> Option<(i32, i32)> {
+        match self {
+            Compression::None => None,
+            Compression::Brotli => Some((0, 11)),
+            Compression::Xz | Compression::XzParallel => Some((0, 9)),
+            Compression::Zstd
+            | Compression::ZstdParallel
+            | Compression::ZstdLong
+            | Compression::ZstdDictionary => Some((1, 22)),
+        }
+    }
+}
+
+/// Compression algorithm plus optional level, written as `zstd`, `zstd-19` or `xz-parallel-6` in the config.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct CompressionSetting {
+    pub compression: Compression,
+    pub level: Option<i32>,
+}
+
+impl CompressionSetting {
+    pub fn new(compression: Compression) -> Self {
+        Self {
+            compression,
+            level: None,
+        }
+    }
+
+    fn level(&self) -> Level {
+        match (self.level, self.compression) {
+            (Some(level), _) => Level::Precise(level),
+            (None, Compression::XzParallel) => Level::Best,
+            (None, _) => Level::Default,
+        }
+    }
+}
+
+impl From<Compression> for CompressionSetting {
+    fn from(compression: Compression) -> Self {
+        Self::new(compression)
+    }
+}
+
+impl FromStr for CompressionSetting {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, level) = match s.rsplit_once('-') {
+            Some((name, level)) if level.chars().all(|c| c.is_ascii_digit()) => {
+                (name, Some(level.parse::<i32>()?))
+            }
+            _ => (s, None),
+        };
+        let Some((_, compression)) = Compression::NAMES.iter().find(|(n, _)| *n == name) else {
+            let known = Compression::NAMES.map(|(n, _)| n).join(", ");
+            bail!(
+                "Unknown compression \"{}\", expected one of {}",
+                name,
+                known
+            );
+        };
+        if let Some(level) = level {
+            match compression.level_range() {
+                None => bail!("Compression \"{}\" does not support a level", name),
+                Some((min, max)) if level < min || level > max => {
+                    bail!(
+                        "Compression level {} for \"{}\" is out of range {}..={}",
+                        level,
+                        name,
+                        min,
+                        max
+                    )
+                }
+                _ => {}
+            }
+        }
+        Ok(Self {
+            compression: *compression,
+            level,
+        })
+    }
+}
+
+impl TryFrom<String> for CompressionSetting {
+    type Error = anyhow::Error;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl Display for CompressionSetting {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.level {
+            Some(level) => write!(f, "{}-{}", self.compression.name(), level),
+            None => write!(f, "{}", self.compression.name()),
+        }
+    }
+}
+
+impl JsonSchema for CompressionSetting {
+    fn schema_name() -> Cow<'static, str> {
+        "CompressionSetting".into()
+    }
+
+    fn json_schema(_: &mut SchemaGenerator) -> Schema {
+        let names = Compression::NAMES.map(|(name, _)| name).join("|");
+        json_schema!({
+            "type": "string",
+            "description": "Compression with an optional level, e.g. zstd or zstd-19",
+            "pattern": format!("^({})(-[0-9]+)?$", names),
+        })
+    }
+}
+
+impl From<CompressionSetting> for String {
+    fn from(value: CompressionSetting) -> Self {
+        value.to_string()
+    }
+}
+
+/// Maps a glob (relative to the config root) to a compression, written as `"**/*.wasm": zstd-19`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(
+    try_from = "BTreeMap<SmolStr, CompressionSetting>",
+    into = "BTreeMap<SmolStr, CompressionSetting>"
+)]
+pub struct CompressionRule {
+    pub glob: Pattern,
+    pub compression: CompressionSetting,
+}
+
+impl JsonSchema for CompressionRule {
+    fn schema_name() -> Cow<'static, str> {
+        "CompressionRule".into()
+    }
+
+    fn json_schema(generator: &mut SchemaGenerator) -> Schema {
+        json_schema!({
+            "type": "object",
+            "description": "A single \"glob: compression\" entry",
+            "additionalProperties": generator.subschema_for::<CompressionSetting>(),
+            "minProperties": 1,
+            "maxProperties": 1,
+        })
+    }
+}
+
+impl TryFrom<BTreeMap<SmolStr, CompressionSetting>> for CompressionRule {
+    type Error = anyhow::Error;
+
+    fn try_from(value: BTreeMap<SmolStr, CompressionSetting>) -> Result<Self, Self::Error> {
+        if value.len() != 1 {
+            bail!(
+                "A compression rule needs exactly one \"glob: compression\" entry, got {}",
+                value.len()
+            );
+        }
+        let (glob, compression) = value.into_iter().next().expect("checked length");
+        let glob = Pattern::new(&glob)
+            .with_context(|| format!("Invalid compression rule glob {}", glob))?;
+        Ok(Self { glob, compression })
+    }
+}
+
+impl From<CompressionRule> for BTreeMap<SmolStr, CompressionSetting> {
+    fn from(value: CompressionRule) -> Self {
+        BTreeMap::from([(value.glob.as_str().into(), value.compression)])
+    }
+}
+
+/// Everything needed to pick a compression for a file, resolved from the config for one project.
+#[derive(Debug, Clone, Default)]
+pub struct CompressionPolicy {
+    pub root: PathBuf,
+    pub rules: Vec<CompressionRule>,
+    pub default_compression: Option<CompressionSetting>,
+    pub min_savings_percent: Option<u8>,
+    pub dictionary: Option<Dictionary>,
+    pub chunking: Option<ChunkingConfig>,
+}
+
+impl CompressionPolicy {
+    pub fn uncompressed() -> Self {
+        Self {
+            rules: vec![CompressionRule {
+                glob: Pattern::new("**").expect("valid glob"),
+                compression: Compression::None.into(),
+            }],
+            ..Default::default()
+        }
+    }
+
+    pub fn matching_rule(&self, path: &Path) -> Option<&CompressionRule> {
+        let relative = path.strip_prefix(&self.root).unwrap_or(path);
+        self.rules
+            .iter()
+            .find(|rule| rule.glob.matches_path(relative))
+    }
+
+    /// Whether a blob compressed from `original_size` to `compressed_size` saved enough to keep it.
+    pub fn is_worth_it(&self, original_size: u64, compressed_size: u64) -> bool {
+        match self.min_savings_percent {
+            None => true,
+            Some(percent) => {
+                compressed_size * 100 <= original_size * (100 - percent.min(100) as u64)
+            }
+        }
+    }
+}
+
+fn worker_threads() -> u32 {
+    (num_cpus::get_physical() as u32).saturating_sub(1).max(1)
+}
+
+fn require_dictionary(dictionary: Option<&[u8]>) -> std::io::Result<&[u8]> {
+    dictionary.ok_or(std::io::Error::new(
+        std::io::ErrorKind::InvalidInput,
+        "zstd dictionary compression without a dictionary",
+    ))
+}
+
+pub async fn compress<R, W>(
+    setting: CompressionSetting,
+    dictionary: Option<&[u8]>,
+    reader: R,
+    writer: &mut W,
+) -> std::io::Result<u64>
+where
+    R: AsyncBufRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut reader = reader;
+    let level = setting.level();
+    match setting.compression {
+        Compression::None => copy_buf(&mut reader, writer).await,
+        Compression::Brotli => copy(&mut BrotliEncoder::with_quality(reader, level), writer).await,
+        Compression::Xz => copy(&mut XzEncoder::with_quality(reader, level), writer).await,
+        Compression::XzParallel => {
+            let threads = NonZeroU32::new(worker_threads()).expect("at least one thread");
+            copy(&mut XzEncoder::parallel(reader, level, threads), writer).await
+        }
+        Compression::Zstd => copy(&mut ZstdEncoder::with_quality(reader, level), writer).await,
+        Compression::ZstdParallel => {
+            let params = [CParameter::nb_workers(worker_threads())];
+            let mut encoder = ZstdEncoder::with_quality_and_params(reader, level, &params);
+            copy(&mut encoder, writer).await
+        }
+        Compression::ZstdLong => {
+            let params = [
+                CParameter::enable_long_distance_matching(true),
+                CParameter::window_log(ZSTD_LONG_WINDOW_LOG),
+                CParameter::nb_workers(worker_threads()),
+            ];
+            let mut encoder = ZstdEncoder::with_quality_and_params(reader, level, &params);
+            copy(&mut encoder, writer).await
+        }
+        Compression::ZstdDictionary => {
+            let dictionary = require_dictionary(dictionary)?;
+            copy(
+                &mut ZstdEncoder::with_dict(reader, level, dictionary)?,
+                writer,
+            )
+            .await
+        }
+    }
+}
+
+pub async fn decompress<R, W>(
+    compression: Compression,
+    dictionary: Option<&[u8]>,
+    reader: R,
+    writer: &mut W,
+) -> std::io::Result<u64>
+where
+    R: AsyncBufRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut reader = reader;
+    match compression {
+        Compression::None => copy_buf(&mut reader, writer).await,
+        Compression::Brotli => copy(&mut BrotliDecoder::new(reader), writer).await,
+        Compression::Xz => copy(&mut XzDecoder::with_mem_limit(reader, XZ_MEM_LIMIT), writer).await,
+        Compression::XzParallel => {
+            let threads = NonZeroU32::new(worker_threads()).expect("at least one thread");
+            let mut decoder = XzDecoder::parallel_with_mem_limit(reader, threads, XZ_MEM_LIMIT);
+            copy(&mut decoder, writer).await
+        }
+        Compression::Zstd | Compression::ZstdParallel => {
+            copy(&mut ZstdDecoder::new(reader), writer).await
+        }
+        Compression::ZstdLong => {
+            let params = [DParameter::window_log_max(ZSTD_LONG_WINDOW_LOG)];
+            copy(&mut ZstdDecoder::with_params(reader, &params), writer).await
+        }
+        Compression::ZstdDictionary => {
+            let dictionary = require_dictionary(dictionary)?;
+            copy(&mut ZstdDecoder::with_dict(reader, dictionary)?, writer).await
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn round_trip(setting: CompressionSetting, dictionary: Option<&[u8]>, data: &[u8]) {
+        let mut compressed = Vec::new();
+        compress(setting, dictionary, data, &mut compressed)
+            .await
+            .unwrap();
+        assert!(
+            compressed.len() < data.len(),
+            "{} did not compress",
+            setting
+        );
+        let mut decompressed = Vec::new();
+        decompress(
+            setting.compression,
+            dictionary,
+            compressed.as_slice(),
+            &mut decompressed,
+        )
+        .await
+        .unwrap();
+        assert_eq!(decompressed, data, "{} changed the content", setting);
+    }
+
+    #[tokio::test]
+    async fn zstd_variants_round_trip() {
+        let data = "a line of text\n".repeat(2000);
+        for name in ["zstd", "zstd-19", "zstd-parallel", "zstd-long"] {
+            round_trip(name.parse().unwrap(), None, data.as_bytes()).await;
+        }
+        round_trip(
+            "zstd-dictionary".parse().unwrap(),
+            Some(b"a line of text\n"),
+            data.as_bytes(),
+        )
+        .await;
+    }
+
+    #[tokio::test]
+    async fn zstd_dictionary_requires_a_dictionary() {
+        let setting = Compression::ZstdDictionary.into();
+        let error = compress(setting, None, b"data".as_slice(), &mut Vec::new())
+            .await
+            .unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn levels_default_per_compression() {
+        let level = |s: &str| s.parse::<CompressionSetting>().unwrap().level();
+        assert!(matches!(level("zstd"), Level::Default));
+        assert!(matches!(level("zstd-long"), Level::Default));
+        assert!(matches!(level("xz-parallel"), Level::Best));
+        assert!(matches!(level("zstd-19"), Level::Precise(19)));
+        assert!(matches!(level("xz-parallel-6"), Level::Precise(6)));
+    }
+
+    #[test]
+    fn settings_parse_and_display() {
+        let setting: CompressionSetting = "zstd-long-22".parse().unwrap();
+        assert_eq!(setting.compression, Compression::ZstdLong);
+        assert_eq!(setting.level, Some(22));
+        assert_eq!(setting.to_string(), "zstd-long-22");
+        assert_eq!(
+            "zstd-parallel".parse::<CompressionSetting>().unwrap(),
+            Compression::ZstdParallel.into()
+        );
+
+        for (input, message) in [
+            ("zstd-23", "out of range"),
+            ("none-1", "does not support a level"),
+            ("lz4", "Unknown compression"),
+        ] {
+            let error = input.parse::<CompressionSetting>().unwrap_err();
+            assert!(error.to_string().contains(message), "{}", error);
+        }
+    }
+
+    fn rule(glob: &str, compression: &str) -> CompressionRule {
+        CompressionRule {
+            glob: Pattern::new(glob).unwrap(),
+            compression: compression.parse().unwrap(),
+        }
+    }
+
+    #[test]
+    fn first_matching_rule_wins() {
+        let policy = CompressionPolicy {
+            root: PathBuf::from("/repo"),
+            rules: vec![
+                rule("dist/*.wasm", "zstd-19"),
+                rule("**/*.wasm", "brotli"),
+                rule("**", "none"),
+            ],
+            ..Default::default()
+        };
+        let compression = |path: &str| {
+            policy
+                .matching_rule(Path::new(path))
+                .map(|rule| rule.compression.to_string())
+        };
+        assert_eq!(
+            compression("/repo/dist/app.wasm").as_deref(),
+            Some("zstd-19")
+        );
+        assert_eq!(compression("/repo/lib/app.wasm").as_deref(), Some("brotli"));
+        assert_eq!(compression("/repo/dist/app.js").as_deref(), Some("none"));
+        assert_eq!(compression("dist/app.wasm").as_deref(), Some("zstd-19"));
+        assert!(
+            CompressionPolicy::default()
+                .matching_rule(Path::new("a"))
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn min_savings_decide_whether_compression_is_kept() {
+        let policy = |percent| CompressionPolicy {
+            min_savings_percent: percent,
+            ..Default::default()
+        };
+        assert!(policy(None).is_worth_it(100, 100));
+        assert!(policy(Some(10)).is_worth_it(100, 90));
+        assert!(!policy(Some(10)).is_worth_it(100, 91));
+        assert!(!policy(Some(100)).is_worth_it(100, 1));
+        assert!(policy(Some(100)).is_worth_it(100, 0));
+    }
+}
@@ -0,0 +1,145 @@
+use std::{
+    fs::{self, File},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use anyhow::{Context, bail};
+use async_tempfile::TempFile;
+use blake3::Hash;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use smol_str::{SmolStr, ToSmolStr};
+use tokio::io::AsyncWriteExt;
+use tracing::{debug, info};
+
+use crate::cache::{
+    compression::{Compression, CompressionPolicy},
+    file::CachedFile,
+};
+
+pub const DICTIONARY_DIR: &str = "dictionaries";
+/// Dictionaries only pay off for small files, larger ones are compressed without.
+pub const DICTIONARY_MAX_FILE_SIZE: u64 = 1024 * 1024;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DictionaryReference {
+    pub hash: SmolStr,
+    pub created: DateTime<Utc>,
+    pub samples: usize,
+}
+
+#[derive(Debug, Clone)]
+pub struct Dictionary {
+    pub hash: SmolStr,
+    pub data: Arc<Vec<u8>>,
+}
+
+impl Dictionary {
+    fn reference_path(cache_dir: &Path, project: &str) -> PathBuf {
+        cache_dir
+            .join(DICTIONARY_DIR)
+            .join(format!("{}.json", project))
+    }
+
+    pub fn open(cache_dir: &Path, hash: &Hash) -> anyhow::Result<Self> {
+        let blob = CachedFile::open(cache_dir, hash)?;
+        if blob.data.compression != Compression::None {
+            bail!(
+                "Dictionary {} is stored with {:?} instead of as-is",
+                hash,
+                blob.data.compression
+            );
+        }
+        let data = blob
+            .read_stored()
+            .with_context(|| format!("Could not read dictionary {}", hash))?;
+        Ok(Self {
+            hash: hash.to_smolstr(),
+            data: Arc::new(data),
+        })
+    }
+
+    pub fn load(cache_dir: &Path, project: &str) -> anyhow::Result<Option<Self>> {
+        let reference_path = Self::reference_path(cache_dir, project);
+        if !reference_path.exists() {
+            return Ok(None);
+        }
+        let reference: DictionaryReference = serde_json::from_reader(File::open(&reference_path)?)
+            .with_context(|| format!("Could not parse {:?}", reference_path))?;
+        let dictionary = Self::open(cache_dir, &Hash::from_hex(reference.hash.as_bytes())?)?;
+        debug!(
+            "Using dictionary {} for project {}",
+            dictionary.hash, project
+        );
+        Ok(Some(dictionary))
+    }
+
+    /// Trains a dictionary from up to `max_samples` of the given files, evenly spread over them.
+    pub fn train(
+        paths: &[PathBuf],
+        max_samples: usize,
+        max_size: usize,
+    ) -> anyhow::Result<(Vec<u8>, usize)> {
+        let mut candidates = paths
+            .iter()
+            .filter(|path| {
+                path.metadata()
+                    .is_ok_and(|m| m.len() > 0 && m.len() <= DICTIONARY_MAX_FILE_SIZE)
+            })
+            .collect::<Vec<_>>();
+        candidates.sort();
+        if candidates.is_empty() {
+            bail!(
+                "No output files of at most {} bytes to train a dictionary from",
+                DICTIONARY_MAX_FILE_SIZE
+            );
+        }
+        let step = candidates.len().div_ceil(max_samples.max(1));
+        let samples = candidates
+            .into_iter()
+            .step_by(step)
+            .map(|path| fs::read(path).with_context(|| format!("Could not read {:?}", path)))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        info!("Training dictionary from {} sample files", samples.len());
+        let dictionary = zstd::dict::from_samples(&samples, max_size).with_context(|| {
+            format!("Could not train dictionary from {} samples", samples.len())
+        })?;
+        Ok((dictionary, samples.len()))
+    }
+
+    pub async fn store(
+        cache_dir: &Path,
+        project: &str,
+        data: Vec<u8>,
+        samples: usize,
+    ) -> anyhow::Result<Self> {
+        // a fixed name in /tmp would be shared by every checkout on the machine
+        tokio::fs::create_dir_all(cache_dir).await?;
+        let mut temp_file = TempFile::new_in(cache_dir).await?;
+        temp_file.write_all(&data).await?;
+        temp_file.flush().await?;
+        let (hash, size) = CachedFile::hash_path(temp_file.file_path())?;
+        CachedFile::create(
+            cache_dir.to_owned(),
+            temp_file.file_path().to_owned(),
+            hash,
+            size,
+            Arc::new(CompressionPolicy::uncompressed()),
+        )
+        .await?;
+
+        let reference = DictionaryReference {
+            hash: hash.to_smolstr(),
+            created: Utc::now(),
+            samples,
+        };
+        let reference_path = Self::reference_path(cache_dir, project);
+        tokio::fs::create_dir_all(cache_dir.join(DICTIONARY_DIR)).await?;
+        tokio::fs::write(&reference_path, serde_json::to_string(&reference)?).await?;
+        Ok(Self {
+            hash: reference.hash,
+            data: Arc::new(data),
+        })
+    }
+}
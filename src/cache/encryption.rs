@@ -0,0 +1,445 @@
+use std::{
+    env, fs,
+    io::{Error, ErrorKind},
+    path::{Path, PathBuf},
+    pin::Pin,
+    sync::OnceLock,
+    task::{Context as TaskContext, Poll, ready},
+};
+
+use anyhow::{Context, anyhow, bail};
+use chacha20poly1305::{
+    AeadCore, KeyInit, XChaCha20Poly1305, XNonce,
+    aead::{
+        Aead, OsRng, Payload,
+        stream::{DecryptorBE32, EncryptorBE32},
+    },
+};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use smol_str::{SmolStr, ToSmolStr};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, ReadBuf};
+
+/// Hex encoded 32 byte key, takes precedence over `key_file`.
+pub const ENCRYPTION_KEY_ENV: &str = "CACHE_ENCRYPTION_KEY";
+const KEY_LENGTH: usize = 32;
+/// XChaCha20 nonce without the 5 bytes STREAM uses for the segment counter and last flag.
+const STREAM_NONCE_LENGTH: usize = 19;
+const SEGMENT_SIZE: usize = 64 * 1024;
+const TAG_SIZE: usize = 16;
+const ENCRYPTED_SEGMENT_SIZE: usize = SEGMENT_SIZE + TAG_SIZE;
+
+type Key = chacha20poly1305::Key;
+
+static ENCRYPTION_KEY: OnceLock<Key> = OnceLock::new();
+
+/// Blobs and command records are encrypted with XChaCha20-Poly1305 once `encryption` is
+/// configured, compression still happens before encryption.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, JsonSchema)]
+#[serde(default, deny_unknown_fields)]
+pub struct EncryptionConfig {
+    /// File containing the hex encoded key, relative to the config file.
+    pub key_file: Option<PathBuf>,
+}
+
+impl EncryptionConfig {
+    /// Reads the key and makes it available to every cache operation of this process.
+    pub fn install_key(&self, root: &Path) -> anyhow::Result<()> {
+        let key = match (env::var(ENCRYPTION_KEY_ENV), &self.key_file) {
+            (Ok(key), _) => key,
+            (Err(_), Some(key_file)) => {
+                let path = root.join(key_file);
+                fs::read_to_string(&path)
+                    .with_context(|| format!("Could not read encryption key {:?}", path))?
+            }
+            (Err(_), None) => bail!(
+                "Encryption is configured but neither {} nor key_file is set",
+                ENCRYPTION_KEY_ENV
+            ),
+        };
+        let key = parse_key(&key)?;
+        if ENCRYPTION_KEY.get_or_init(|| key) != &key {
+            bail!("A different encryption key is already in use");
+        }
+        Ok(())
+    }
+}
+
+fn parse_key(value: &str) -> anyhow::Result<Key> {
+    let key = hex::decode(value.trim()).context("Invalid encryption key")?;
+    if key.len() != KEY_LENGTH {
+        bail!(
+            "Encryption key needs {} bytes, got {}",
+            KEY_LENGTH,
+            key.len()
+        );
+    }
+    Ok(Key::clone_from_slice(&key))
+}
+
+pub fn installed_key() -> Option<&'static Key> {
+    ENCRYPTION_KEY.get()
+}
+
+pub fn require_key() -> std::io::Result<&'static Key> {
+    installed_key().ok_or_else(|| {
+        Error::new(
+            ErrorKind::PermissionDenied,
+            format!(
+                "Entry is encrypted but no key is set, set {}",
+                ENCRYPTION_KEY_ENV
+            ),
+        )
+    })
+}
+
+fn invalid(message: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, message)
+}
+
+/// Small record encrypted as a whole, stored hex encoded in place of the plain JSON.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SealedRecord {
+    pub nonce: SmolStr,
+    pub data: SmolStr,
+}
+
+impl SealedRecord {
+    /// `associated_data` binds the record to its location, e.g. the command hash.
+    pub fn seal(plain: &[u8], associated_data: &[u8]) -> anyhow::Result<Option<Self>> {
+        installed_key()
+            .map(|key| Self::seal_with(key, plain, associated_data))
+            .transpose()
+    }
+
+    fn seal_with(key: &Key, plain: &[u8], associated_data: &[u8]) -> anyhow::Result<Self> {
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let data = XChaCha20Poly1305::new(key)
+            .encrypt(
+                &nonce,
+                Payload {
+                    msg: plain,
+                    aad: associated_data,
+                },
+            )
+            .map_err(|_| anyhow!("Could not encrypt record"))?;
+        Ok(Self {
+            nonce: hex::encode(nonce).to_smolstr(),
+            data: hex::encode(data).to_smolstr(),
+        })
+    }
+
+    pub fn open(&self, associated_data: &[u8]) -> anyhow::Result<Vec<u8>> {
+        self.open_with(require_key()?, associated_data)
+    }
+
+    fn open_with(&self, key: &Key, associated_data: &[u8]) -> anyhow::Result<Vec<u8>> {
+        let nonce = hex::decode(self.nonce.as_bytes())?;
+        if nonce.len() != XNonce::default().len() {
+            bail!("Encrypted record has an invalid nonce");
+        }
+        XChaCha20Poly1305::new(key)
+            .decrypt(
+                XNonce::from_slice(&nonce),
+                Payload {
+                    msg: &hex::decode(self.data.as_bytes())?,
+                    aad: associated_data,
+                },
+            )
+            .map_err(|_| anyhow!("Encrypted record does not match the key or was modified"))
+    }
+}
+
+/// Decrypts a whole blob written by [`EncryptingWriter`] in memory.
+pub fn decrypt_bytes(bytes: &[u8], associated_data: &[u8]) -> std::io::Result<Vec<u8>> {
+    decrypt_bytes_with(require_key()?, bytes, associated_data)
+}
+
+fn decrypt_bytes_with(key: &Key, bytes: &[u8], associated_data: &[u8]) -> std::io::Result<Vec<u8>> {
+    if bytes.len() < STREAM_NONCE_LENGTH {
+        return Err(invalid("encrypted blob is truncated"));
+    }
+    let (nonce, mut segments) = bytes.split_at(STREAM_NONCE_LENGTH);
+    let mut decryptor = DecryptorBE32::<XChaCha20Poly1305>::new(key, nonce.into());
+    let mut plain = Vec::with_capacity(bytes.len());
+    while segments.len() > ENCRYPTED_SEGMENT_SIZE {
+        let (segment, rest) = segments.split_at(ENCRYPTED_SEGMENT_SIZE);
+        let payload = Payload {
+            msg: segment,
+            aad: associated_data,
+        };
+        plain.extend(
+            decryptor
+                .decrypt_next(payload)
+                .map_err(|_| invalid("decryption failed"))?,
+        );
+        segments = rest;
+    }
+    let payload = Payload {
+        msg: segments,
+        aad: associated_data,
+    };
+    plain.extend(
+        decryptor
+            .decrypt_last(payload)
+            .map_err(|_| invalid("decryption failed"))?,
+    );
+    Ok(plain)
+}
+
+/// Encrypts everything written to it in segments, the last segment is only written on shutdown.
+pub struct EncryptingWriter<W> {
+    inner: W,
+    encryptor: Option<EncryptorBE32<XChaCha20Poly1305>>,
+    associated_data: Vec<u8>,
+    plain: Vec<u8>,
+    encrypted: Vec<u8>,
+    written: usize,
+}
+
+impl<W: AsyncWrite + Unpin> EncryptingWriter<W> {
+    pub fn new(inner: W, associated_data: &[u8]) -> std::io::Result<Self> {
+        Ok(Self::with_key(inner, require_key()?, associated_data))
+    }
+
+    fn with_key(inner: W, key: &Key, associated_data: &[u8]) -> Self {
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let nonce = &nonce[..STREAM_NONCE_LENGTH];
+        Self {
+            inner,
+            encryptor: Some(EncryptorBE32::new(key, nonce.into())),
+            associated_data: associated_data.to_vec(),
+            plain: Vec::with_capacity(SEGMENT_SIZE),
+            encrypted: nonce.to_vec(),
+            written: 0,
+        }
+    }
+
+    fn poll_write_encrypted(&mut self, cx: &mut TaskContext<'_>) -> Poll<std::io::Result<()>> {
+        while self.written < self.encrypted.len() {
+            let written =
+                ready!(Pin::new(&mut self.inner).poll_write(cx, &self.encrypted[self.written..]))?;
+            if written == 0 {
+                return Poll::Ready(Err(ErrorKind::WriteZero.into()));
+            }
+            self.written += written;
+        }
+        self.encrypted.clear();
+        self.written = 0;
+        Poll::Ready(Ok(()))
+    }
+
+    fn encrypt_segment(&mut self, last: bool) -> std::io::Result<()> {
+        let payload = Payload {
+            msg: &self.plain,
+            aad: &self.associated_data,
+        };
+        let encrypted = match (last, self.encryptor.take()) {
+            (false, Some(mut encryptor)) => {
+                let encrypted = encryptor.encrypt_next(payload);
+                self.encryptor = Some(encryptor);
+                encrypted
+            }
+            (true, Some(encryptor)) => encryptor.encrypt_last(payload),
+            (_, None) => return Err(Error::other("encrypted stream is already finished")),
+        };
+        self.encrypted = encrypted.map_err(|_| Error::other("encryption failed"))?;
+        self.plain.clear();
+        Ok(())
+    }
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for EncryptingWriter<W> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        ready!(self.poll_write_encrypted(cx))?;
+        let accepted = buf.len().min(SEGMENT_SIZE - self.plain.len());
+        self.plain.extend_from_slice(&buf[..accepted]);
+        if self.plain.len() == SEGMENT_SIZE {
+            self.encrypt_segment(false)?;
+        }
+        Poll::Ready(Ok(accepted))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<std::io::Result<()>> {
+        ready!(self.poll_write_encrypted(cx))?;
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        ready!(self.poll_write_encrypted(cx))?;
+        if self.encryptor.is_some() {
+            self.encrypt_segment(true)?;
+            ready!(self.poll_write_encrypted(cx))?;
+        }
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+/// Decrypts a blob written by [`EncryptingWriter`], fails if it was modified or truncated.
+pub struct DecryptingReader<R> {
+    inner: R,
+    decryptor: Option<DecryptorBE32<XChaCha20Poly1305>>,
+    associated_data: Vec<u8>,
+    encrypted: Vec<u8>,
+    plain: Vec<u8>,
+    read: usize,
+    eof: bool,
+}
+
+impl<R: AsyncRead + Unpin> DecryptingReader<R> {
+    pub async fn new(inner: R, associated_data: &[u8]) -> std::io::Result<Self> {
+        Self::with_key(inner, require_key()?, associated_data).await
+    }
+
+    async fn with_key(mut inner: R, key: &Key, associated_data: &[u8]) -> std::io::Result<Self> {
+        let mut nonce = [0u8; STREAM_NONCE_LENGTH];
+        inner
+            .read_exact(&mut nonce)
+            .await
+            .map_err(|_| invalid("encrypted blob is truncated"))?;
+        Ok(Self {
+            inner,
+            decryptor: Some(DecryptorBE32::new(key, (&nonce).into())),
+            associated_data: associated_data.to_vec(),
+            encrypted: Vec::with_capacity(ENCRYPTED_SEGMENT_SIZE + 1),
+            plain: Vec::new(),
+            read: 0,
+            eof: false,
+        })
+    }
+
+    /// Reads until a whole segment plus one byte is buffered, the extra byte tells that the
+    /// segment is not the last one.
+    fn poll_fill(&mut self, cx: &mut TaskContext<'_>) -> Poll<std::io::Result<()>> {
+        while !self.eof && self.encrypted.len() <= ENCRYPTED_SEGMENT_SIZE {
+            let start = self.encrypted.len();
+            self.encrypted.resize(ENCRYPTED_SEGMENT_SIZE + 1, 0);
+            let mut buf = ReadBuf::new(&mut self.encrypted[start..]);
+            let result = Pin::new(&mut self.inner).poll_read(cx, &mut buf);
+            let filled = buf.filled().len();
+            self.encrypted.truncate(start + filled);
+            ready!(result)?;
+            self.eof = filled == 0;
+        }
+        Poll::Ready(Ok(()))
+    }
+
+    fn decrypt_segment(&mut self) -> std::io::Result<()> {
+        let last = self.encrypted.len() <= ENCRYPTED_SEGMENT_SIZE;
+        let length = self.encrypted.len().min(ENCRYPTED_SEGMENT_SIZE);
+        let payload = Payload {
+            msg: &self.encrypted[..length],
+            aad: &self.associated_data,
+        };
+        let plain = match (last, self.decryptor.take()) {
+            (false, Some(mut decryptor)) => {
+                let plain = decryptor.decrypt_next(payload);
+                self.decryptor = Some(decryptor);
+                plain
+            }
+            (true, Some(decryptor)) => decryptor.decrypt_last(payload),
+            (_, None) => return Ok(()),
+        };
+        self.plain = plain.map_err(|_| invalid("decryption failed"))?;
+        self.read = 0;
+        self.encrypted.drain(..length);
+        Ok(())
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for DecryptingReader<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        while self.read == self.plain.len() {
+            if self.decryptor.is_none() {
+                return Poll::Ready(Ok(()));
+            }
+            ready!(self.poll_fill(cx))?;
+            self.decrypt_segment()?;
+        }
+        let amount = buf.remaining().min(self.plain.len() - self.read);
+        let start = self.read;
+        buf.put_slice(&self.plain[start..start + amount]);
+        self.read += amount;
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::AsyncWriteExt;
+
+    use super::*;
+
+    fn key(byte: u8) -> Key {
+        parse_key(&hex::encode([byte; KEY_LENGTH])).unwrap()
+    }
+
+    async fn encrypt(plain: &[u8], associated_data: &[u8]) -> Vec<u8> {
+        let mut writer = EncryptingWriter::with_key(Vec::new(), &key(1), associated_data);
+        writer.write_all(plain).await.unwrap();
+        writer.shutdown().await.unwrap();
+        writer.inner
+    }
+
+    async fn decrypt(encrypted: &[u8], associated_data: &[u8]) -> std::io::Result<Vec<u8>> {
+        let mut reader = DecryptingReader::with_key(encrypted, &key(1), associated_data).await?;
+        let mut plain = Vec::new();
+        reader.read_to_end(&mut plain).await?;
+        assert_eq!(
+            decrypt_bytes_with(&key(1), encrypted, associated_data)?,
+            plain
+        );
+        Ok(plain)
+    }
+
+    #[test]
+    fn keys_need_32_hex_encoded_bytes() {
+        assert!(parse_key(&format!("{}\n", hex::encode([7; KEY_LENGTH]))).is_ok());
+        assert!(parse_key(&hex::encode([7; 16])).is_err());
+        assert!(parse_key("not hex").is_err());
+    }
+
+    #[test]
+    fn sealed_records_only_open_with_their_key_and_location() {
+        let sealed = SealedRecord::seal_with(&key(1), b"{}", b"command").unwrap();
+        assert_eq!(sealed.open_with(&key(1), b"command").unwrap(), b"{}");
+        assert!(sealed.open_with(&key(2), b"command").is_err());
+        assert!(sealed.open_with(&key(1), b"other command").is_err());
+    }
+
+    #[tokio::test]
+    async fn streams_round_trip_across_segments() {
+        for length in [0, 10, SEGMENT_SIZE, 2 * SEGMENT_SIZE + 17] {
+            let plain = (0..length).map(|i| i as u8).collect::<Vec<_>>();
+            let encrypted = encrypt(&plain, b"blob").await;
+            assert_eq!(decrypt(&encrypted, b"blob").await.unwrap(), plain);
+        }
+    }
+
+    #[tokio::test]
+    async fn modified_or_truncated_streams_are_rejected() {
+        let plain = vec![42; 2 * SEGMENT_SIZE + 17];
+        let encrypted = encrypt(&plain, b"blob").await;
+        assert!(decrypt(&encrypted, b"other blob").await.is_err());
+
+        let mut flipped = encrypted.clone();
+        flipped[STREAM_NONCE_LENGTH + 5] ^= 1;
+        assert!(decrypt(&flipped, b"blob").await.is_err());
+
+        // dropping the last segment leaves a stream that ends on a non-last segment
+        let truncated = &encrypted[..STREAM_NONCE_LENGTH + 2 * ENCRYPTED_SEGMENT_SIZE];
+        assert!(decrypt(truncated, b"blob").await.is_err());
+        assert!(decrypt(&encrypted[..3], b"blob").await.is_err());
+    }
+}
@@ -1,54 +1,132 @@
 use std::{
-    num::NonZeroU32,
+    collections::HashMap,
+    io::{Read, Seek},
     path::{Path, PathBuf},
+    pin::Pin,
+    sync::{Arc, LazyLock, Mutex},
+    task::{Context as TaskContext, Poll},
     time::SystemTime,
 };
 
 use anyhow::{Context, Ok, bail};
-use async_compression::tokio::bufread::{BrotliDecoder, BrotliEncoder, XzDecoder, XzEncoder};
 use blake3::Hash;
 use chrono::{DateTime, Utc};
-use file_type::FileType;
 use nonempty::NonEmpty;
 use serde::{Deserialize, Serialize};
 use smol_str::{SmolStr, ToSmolStr};
 use tokio::{
     fs::{File, create_dir_all},
-    io::{AsyncWriteExt, BufReader, BufWriter, copy, copy_buf, stdout},
+    io::{
+        AsyncBufRead, AsyncReadExt, AsyncSeekExt, AsyncWrite, AsyncWriteExt, BufReader, BufWriter,
+        SeekFrom, copy_buf, sink, stdout,
+    },
 };
 use tracing::{debug, trace};
 
-use crate::{cache::folder::FILE_FOLDER_NAME, error::CacheError};
-
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub enum Compression {
-    None,
-    Brotli,
-    Xz,
-    XzParallel,
-}
+use crate::{
+    cache::{
+        chunk::ChunkingConfig,
+        compression::{Compression, CompressionPolicy, CompressionSetting, compress, decompress},
+        dictionary::{DICTIONARY_MAX_FILE_SIZE, Dictionary},
+        encryption::{DecryptingReader, EncryptingWriter, decrypt_bytes, installed_key},
+        folder::FILE_FOLDER_NAME,
+        format::{FORMAT_VERSION, read_record},
+        pack::PackIndex,
+        sniff::{Content, sniff_file},
+    },
+    error::CacheError,
+};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct StoredCacheFile {
+    #[serde(default)]
+    pub version: u32,
     pub created: DateTime<Utc>,
     pub original_hash: SmolStr,
     pub compression: Compression,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dictionary: Option<SmolStr>,
+    /// Hashes of the content defined chunks, in order, if the file is stored chunked.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub chunks: Option<Vec<SmolStr>>,
+    /// The compressed blob is encrypted, with the original hash as associated data.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub encrypted: bool,
 }
 
+/// Hashes currently being written by [`CachedFile::create`].
+static CREATING: LazyLock<Mutex<HashMap<Hash, Arc<tokio::sync::Mutex<()>>>>> =
+    LazyLock::new(Default::default);
+
 #[derive(Debug, Clone)]
 pub struct CachedFile {
+    /// The hash the file was requested by, its content has to match it.
+    pub hash: Hash,
     pub path: PathBuf,
+    /// Offset and length of the blob if `path` is a pack file.
+    pub range: Option<(u64, u64)>,
     pub data: StoredCacheFile,
+    pub dictionary: Option<Arc<Vec<u8>>>,
+    pub chunks: Vec<CachedFile>,
+}
+
+/// Destinations that already held the cached content are `skipped` instead of rewritten.
+#[derive(Debug, Clone, Default)]
+pub struct RestoredFile {
+    pub restored: Vec<PathBuf>,
+    pub skipped: Vec<PathBuf>,
+}
+
+/// Passes everything through to `inner` and hashes what was written.
+struct HashingWriter<'a, W> {
+    inner: &'a mut W,
+    hasher: blake3::Hasher,
+}
+
+impl<'a, W> HashingWriter<'a, W> {
+    fn new(inner: &'a mut W) -> Self {
+        Self {
+            inner,
+            hasher: blake3::Hasher::new(),
+        }
+    }
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for HashingWriter<'_, W> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let this = &mut *self;
+        let result = Pin::new(&mut *this.inner).poll_write(cx, buf);
+        if let Poll::Ready(std::io::Result::Ok(written)) = &result {
+            this.hasher.update(&buf[..*written]);
+        }
+        result
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut *self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut *self.inner).poll_shutdown(cx)
+    }
 }
-pub const COMPRESSED_FILE_NAME: &'static str = "compressed";
-pub const DATA_FILE_NAME: &'static str = "file.json";
+
+pub const COMPRESSED_FILE_NAME: &str = "compressed";
+pub const DATA_FILE_NAME: &str = "file.json";
 
 impl CachedFile {
     pub fn hash_path(path: &Path) -> anyhow::Result<(Hash, u64)> {
         let mut hasher = blake3::Hasher::new();
         let start = SystemTime::now();
         // blake 3 does file size check already and uses best way to hash (readfile,mmap,parallel)
-        hasher.update_mmap_rayon(&path)?;
+        hasher.update_mmap_rayon(path)?;
 
         let hash = hasher.finalize();
         let elapsed = start.elapsed().expect("Could not measure system time");
@@ -68,219 +146,710 @@ impl CachedFile {
         }
     }
 
-    fn determine_compression(path: &Path, file_size: u64) -> anyhow::Result<Compression> {
+    fn with_available_dictionary(
+        compression: CompressionSetting,
+        policy: &CompressionPolicy,
+    ) -> CompressionSetting {
+        if compression.compression == Compression::ZstdDictionary && policy.dictionary.is_none() {
+            debug!("No dictionary trained for this project, falling back to plain zstd");
+            CompressionSetting {
+                compression: Compression::Zstd,
+                level: compression.level,
+            }
+        } else {
+            compression
+        }
+    }
+
+    fn determine_compression(
+        path: &Path,
+        file_size: u64,
+        policy: &CompressionPolicy,
+    ) -> anyhow::Result<CompressionSetting> {
+        if let Some(rule) = policy.matching_rule(path) {
+            trace!(
+                "Compression rule {} matches {:?} -> {}",
+                rule.glob, path, rule.compression
+            );
+            return Ok(Self::with_available_dictionary(rule.compression, policy));
+        }
         if file_size < 10 * 1024 {
             trace!(
                 "File is too small to even deal with compression {:?} -> as-is",
                 path
             );
-            return Ok(Compression::None);
+            return Ok(Compression::None.into());
         }
 
-        let file_type = FileType::try_from_file(path)?;
-        let media_types = file_type.media_types().join(", ");
-        if media_types.contains(&"text") {
-            trace!("Discovered text file {:?} -> compress brotli", path);
-            Ok(Compression::Brotli)
-        } else if ["zip", "image", "video", "audio", "archive"]
-            .iter()
-            .any(|contains| media_types.contains(contains))
-        {
-            trace!(
-                "Media type {} marks this as already compressed file {:?} -> as-is",
-                media_types, path
-            );
-            Ok(Compression::None)
-        } else if file_size > 1024 * 1024 * 1024 {
-            trace!(
-                ">1gb and Media type {} suggest this file needs compression {:?} -> compress xz parallel",
-                media_types, path
-            );
-            Ok(Compression::XzParallel)
-        } else {
-            trace!(
-                "Media type {} suggest this file needs compression {:?} -> compress xz",
-                media_types, path
-            );
-            Ok(Compression::Xz)
+        match (sniff_file(path)?, policy.default_compression) {
+            (Content::Compressed(format), _) => {
+                trace!(
+                    "Content looks like {} and is already compressed {:?} -> as-is",
+                    format, path
+                );
+                Ok(Compression::None.into())
+            }
+            (Content::HighEntropy(entropy), _) => {
+                trace!(
+                    "Content entropy {:.2} bits/byte is too high to compress {:?} -> as-is",
+                    entropy, path
+                );
+                Ok(Compression::None.into())
+            }
+            // an explicitly configured other compression wins over the dictionary
+            (_, default_compression)
+                if policy.dictionary.is_some()
+                    && file_size <= DICTIONARY_MAX_FILE_SIZE
+                    && default_compression.is_none_or(|c| {
+                        matches!(
+                            c.compression,
+                            Compression::Zstd | Compression::ZstdDictionary
+                        )
+                    }) =>
+            {
+                let level = default_compression.and_then(|c| c.level);
+                trace!(
+                    "Small file with project dictionary {:?} -> zstd dictionary",
+                    path
+                );
+                Ok(CompressionSetting {
+                    compression: Compression::ZstdDictionary,
+                    level,
+                })
+            }
+            (_, Some(default_compression)) => {
+                let default_compression =
+                    Self::with_available_dictionary(default_compression, policy);
+                trace!(
+                    "Using configured compression {} for {:?}",
+                    default_compression, path
+                );
+                Ok(default_compression)
+            }
+            (Content::Text, None) => {
+                trace!("Discovered text file {:?} -> compress brotli", path);
+                Ok(Compression::Brotli.into())
+            }
+            (Content::Binary, None) if file_size > 1024 * 1024 * 1024 => {
+                trace!(
+                    ">1gb binary file needs compression {:?} -> compress xz parallel",
+                    path
+                );
+                Ok(Compression::XzParallel.into())
+            }
+            (Content::Binary, None) => {
+                trace!("Binary file needs compression {:?} -> compress xz", path);
+                Ok(Compression::Xz.into())
+            }
         }
     }
 
+    /// Stores a file unless it is cached already. Commands running in parallel can produce the
+    /// same content, so only one of them writes a given hash at a time.
     pub async fn create(
         cache_dir: PathBuf,
         original_path: PathBuf,
         hash: Hash,
         size: u64,
+        policy: Arc<CompressionPolicy>,
+    ) -> anyhow::Result<Hash> {
+        let lock = CREATING
+            .lock()
+            .expect("file creation locks poisoned")
+            .entry(hash)
+            .or_default()
+            .clone();
+        let result = {
+            let _guard = lock.lock().await;
+            Self::create_unlocked(cache_dir, original_path, hash, size, policy).await
+        };
+        let mut creating = CREATING.lock().expect("file creation locks poisoned");
+        if Arc::strong_count(&lock) == 2 {
+            creating.remove(&hash);
+        }
+        result
+    }
+
+    async fn create_unlocked(
+        cache_dir: PathBuf,
+        original_path: PathBuf,
+        hash: Hash,
+        size: u64,
+        policy: Arc<CompressionPolicy>,
     ) -> anyhow::Result<Hash> {
         let cache_dir = Self::to_file_cache_dir(&cache_dir);
         let file_dir = cache_dir.join(hash.to_string());
-        if file_dir.exists() {
+        if Self::is_stored(&cache_dir, &hash)? {
             debug!("File with hash {} already cached", hash);
             return Ok(hash);
         } else {
             create_dir_all(&file_dir).await?;
         }
-        let compression = Self::determine_compression(&original_path, size)?;
+        let mut compression = Self::determine_compression(&original_path, size, &policy)?;
+        if let Some(chunking) = policy.chunking.as_ref().filter(|c| c.applies_to(size)) {
+            // chunks are shared between files, so they never depend on a project dictionary
+            let chunk_compression = match compression.compression {
+                Compression::ZstdDictionary => CompressionSetting {
+                    compression: Compression::Zstd,
+                    level: compression.level,
+                },
+                _ => compression,
+            };
+            let chunks =
+                Self::create_chunks(&cache_dir, &original_path, chunking, chunk_compression)
+                    .await?;
+            let data = StoredCacheFile {
+                version: FORMAT_VERSION,
+                compression: Compression::None,
+                created: Utc::now(),
+                original_hash: hash.to_smolstr(),
+                dictionary: None,
+                chunks: Some(chunks),
+                encrypted: false,
+            };
+            tokio::fs::write(file_dir.join(DATA_FILE_NAME), serde_json::to_string(&data)?).await?;
+            return Ok(hash);
+        }
 
         let original = File::open(&original_path).await?;
-        let mut target = File::create_new(file_dir.join(COMPRESSED_FILE_NAME)).await?;
-        let mut reader = BufReader::new(original);
-        match compression {
-            Compression::Brotli => {
-                let mut encoder = BrotliEncoder::new(reader);
-                copy(&mut encoder, &mut target).await?;
-            }
-            Compression::None => {
-                copy_buf(&mut reader, &mut target).await?;
-            }
-            Compression::Xz => {
-                let mut encoder = XzEncoder::new(reader);
-                copy(&mut encoder, &mut target).await?;
-            }
-            Compression::XzParallel => {
-                let threads =
-                    NonZeroU32::new(num_cpus::get_physical() as u32 - 1).expect("0 cores? errr...");
-                let mut encoder =
-                    XzEncoder::parallel(reader, async_compression::Level::Best, threads);
-                copy(&mut encoder, &mut target).await?;
-            }
+        let target_path = file_dir.join(COMPRESSED_FILE_NAME);
+        let dictionary = policy.dictionary.as_ref().map(|d| d.data.as_slice());
+        let compressed_size = Self::write_blob(
+            &target_path,
+            compression,
+            dictionary,
+            BufReader::new(original),
+            &hash,
+        )
+        .await?;
+        if compression.compression != Compression::None
+            && !policy.is_worth_it(size, compressed_size)
+        {
+            debug!(
+                "Compressing {:?} with {} saved too little ({} -> {} bytes) -> as-is",
+                original_path, compression, size, compressed_size
+            );
+            compression = Compression::None.into();
+            let original = File::open(&original_path).await?;
+            Self::write_blob(
+                &target_path,
+                compression,
+                None,
+                BufReader::new(original),
+                &hash,
+            )
+            .await?;
         }
         let data = StoredCacheFile {
-            compression,
+            version: FORMAT_VERSION,
+            compression: compression.compression,
             created: Utc::now(),
             original_hash: hash.to_smolstr(),
+            dictionary: policy
+                .dictionary
+                .as_ref()
+                .filter(|_| compression.compression == Compression::ZstdDictionary)
+                .map(|d| d.hash.clone()),
+            chunks: None,
+            encrypted: installed_key().is_some(),
         };
         let mut data_file = File::create_new(file_dir.join(DATA_FILE_NAME)).await?;
 
         let json = serde_json::to_string(&data)?;
-        data_file.write_all(&json.as_bytes()).await?;
+        data_file.write_all(json.as_bytes()).await?;
 
-        target.flush().await?;
         data_file.flush().await?;
         Ok(hash)
     }
 
+    /// Compresses into `target_path`, encrypting the compressed stream if a key is installed.
+    /// Returns the compressed size before encryption.
+    async fn write_blob<R: AsyncBufRead + Unpin>(
+        target_path: &Path,
+        compression: CompressionSetting,
+        dictionary: Option<&[u8]>,
+        reader: R,
+        hash: &Hash,
+    ) -> anyhow::Result<u64> {
+        let mut target = File::create(target_path).await?;
+        let compressed_size = if installed_key().is_some() {
+            let mut writer = EncryptingWriter::new(&mut target, hash.to_string().as_bytes())?;
+            let compressed_size = compress(compression, dictionary, reader, &mut writer).await?;
+            writer.shutdown().await?;
+            compressed_size
+        } else {
+            compress(compression, dictionary, reader, &mut target).await?
+        };
+        target.flush().await?;
+        Ok(compressed_size)
+    }
+
+    async fn create_chunks(
+        cache_dir: &Path,
+        original_path: &Path,
+        chunking: &ChunkingConfig,
+        compression: CompressionSetting,
+    ) -> anyhow::Result<Vec<SmolStr>> {
+        let path = original_path.to_owned();
+        let chunking = chunking.clone();
+        let ranges = tokio::task::spawn_blocking(move || chunking.chunk_file(&path)).await??;
+        let mut new_chunks = 0;
+        for range in &ranges {
+            if Self::is_stored(cache_dir, &range.hash)? {
+                continue;
+            }
+            let chunk_dir = cache_dir.join(range.hash.to_string());
+            new_chunks += 1;
+            create_dir_all(&chunk_dir).await?;
+            let mut original = File::open(original_path).await?;
+            original.seek(SeekFrom::Start(range.offset)).await?;
+            let reader = BufReader::new(original.take(range.length as u64));
+            let target_path = chunk_dir.join(COMPRESSED_FILE_NAME);
+            Self::write_blob(&target_path, compression, None, reader, &range.hash).await?;
+            let data = StoredCacheFile {
+                version: FORMAT_VERSION,
+                compression: compression.compression,
+                created: Utc::now(),
+                original_hash: range.hash.to_smolstr(),
+                dictionary: None,
+                chunks: None,
+                encrypted: installed_key().is_some(),
+            };
+            tokio::fs::write(
+                chunk_dir.join(DATA_FILE_NAME),
+                serde_json::to_string(&data)?,
+            )
+            .await?;
+        }
+        debug!(
+            "Split {:?} into {} chunks, {} of them new",
+            original_path,
+            ranges.len(),
+            new_chunks
+        );
+        Ok(ranges.iter().map(|r| r.hash.to_smolstr()).collect())
+    }
+
+    fn to_cache_root(file_cache_dir: &Path) -> &Path {
+        file_cache_dir.parent().unwrap_or(file_cache_dir)
+    }
+
+    /// Whether the blob exists, either as loose directory or inside a pack.
+    pub fn is_stored(cache_dir: &Path, hash: &Hash) -> anyhow::Result<bool> {
+        let cache_dir = Self::to_file_cache_dir(cache_dir);
+        Ok(cache_dir.join(hash.to_string()).exists()
+            || PackIndex::load(Self::to_cache_root(&cache_dir))?
+                .get(hash)
+                .is_some())
+    }
+
     pub fn open(cache_dir: &Path, hash: &Hash) -> anyhow::Result<Self> {
+        Self::open_record(cache_dir, hash, true)
+    }
+
+    /// Chunks are never chunked themselves, a record pointing back to itself or to another
+    /// chunked record would otherwise recurse forever.
+    fn open_record(cache_dir: &Path, hash: &Hash, allow_chunks: bool) -> anyhow::Result<Self> {
         let cache_dir = Self::to_file_cache_dir(cache_dir);
         let hex = hash.to_string();
         let target_folder = cache_dir.join(hex);
-        let json_file = target_folder.join(DATA_FILE_NAME);
-        let binary_file = target_folder.join(COMPRESSED_FILE_NAME);
-        for path in [&target_folder, &json_file, &binary_file] {
-            if !path.exists() {
-                bail!(CacheError::OpenPathError(path.to_owned()))
+        let (path, range, data) = if target_folder.exists() {
+            let json_file = target_folder.join(DATA_FILE_NAME);
+            let binary_file = target_folder.join(COMPRESSED_FILE_NAME);
+            if !json_file.exists() {
+                bail!(CacheError::OpenPathError(json_file))
+            }
+            let data: StoredCacheFile = read_record(&json_file)?;
+            if data.chunks.is_none() && !binary_file.exists() {
+                bail!(CacheError::OpenPathError(binary_file))
             }
+            (binary_file, None, data)
+        } else if let Some(packed) = PackIndex::load(Self::to_cache_root(&cache_dir))?.get(hash) {
+            trace!("Found {} in pack {:?}", hash, packed.pack);
+            (
+                packed.pack.clone(),
+                Some((packed.offset, packed.length)),
+                packed.data.clone(),
+            )
+        } else {
+            bail!(CacheError::OpenPathError(target_folder))
+        };
+        // the record is as untrusted as the blob, a signed command only vouches for the hash
+        if data.original_hash != hash.to_string() {
+            bail!(CacheError::CorruptBlob {
+                hash: hash.to_smolstr(),
+                reason: format!("record claims to contain {}", data.original_hash),
+            });
+        }
+        if !allow_chunks && data.chunks.is_some() {
+            bail!(CacheError::CorruptBlob {
+                hash: hash.to_smolstr(),
+                reason: "chunk is split into chunks itself".to_owned(),
+            });
         }
-        let json_file = std::fs::File::open(&json_file)?;
-        let data: StoredCacheFile = serde_json::from_reader(&json_file)?;
+        let chunks = data
+            .chunks
+            .iter()
+            .flatten()
+            .map(|chunk| {
+                let chunk = Hash::from_hex(chunk.as_bytes())?;
+                if chunk == *hash {
+                    bail!(CacheError::CorruptBlob {
+                        hash: hash.to_smolstr(),
+                        reason: "record lists itself as a chunk".to_owned(),
+                    });
+                }
+                Self::open_record(&cache_dir, &chunk, false)
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        let dictionary = match &data.dictionary {
+            Some(dictionary_hash) => {
+                let dictionary_hash = Hash::from_hex(dictionary_hash.as_bytes())?;
+                Some(Dictionary::open(&cache_dir, &dictionary_hash)?.data)
+            }
+            None => None,
+        };
 
         Ok({
             Self {
-                path: binary_file,
+                hash: *hash,
+                path,
+                range,
                 data,
+                dictionary,
+                chunks,
             }
         })
     }
 
     pub async fn create_parent(path: &Path) {
-        if let Some(parent) = path.parent() {
-            if !parent.exists() {
-                debug!(
-                    "Creating parent folder structure to restore file {:?}",
-                    parent
-                );
-                let res = create_dir_all(parent).await;
-                if let Err(e) = res {
-                    trace!(
-                        "Multiple threads tried to create parent folder structure, ignore. {}",
-                        e
-                    )
-                }
+        if let Some(parent) = path.parent()
+            && !parent.exists()
+        {
+            debug!(
+                "Creating parent folder structure to restore file {:?}",
+                parent
+            );
+            let res = create_dir_all(parent).await;
+            if let Err(e) = res {
+                trace!(
+                    "Multiple threads tried to create parent folder structure, ignore. {}",
+                    e
+                )
             }
         }
     }
 
-    pub async fn restore(
-        self,
-        destinations: NonEmpty<PathBuf>,
-    ) -> anyhow::Result<NonEmpty<PathBuf>> {
-        let read_file = File::open(&self.path)
-            .await
-            .with_context(|| format!("failed to open cached file binary {:?}", &self.path))?;
-
-        for destination in &destinations {
-            Self::create_parent(destination).await;
+    async fn is_up_to_date(destination: &Path, expected_hash: &str) -> anyhow::Result<bool> {
+        if !destination.is_file() {
+            return Ok(false);
         }
+        let path = destination.to_owned();
+        let (hash, _) = tokio::task::spawn_blocking(move || Self::hash_path(&path)).await??;
+        Ok(hash.to_string() == expected_hash)
+    }
 
-        let original_path = destinations.first();
-        let mut buf_read = BufReader::new(read_file);
-
-        let mut write_file = File::create(&original_path).await.with_context(|| {
-            format!(
-                "creating output file for cached file failed: {:?}",
-                original_path
-            )
-        })?;
-
-        match &self.data.compression {
-            Compression::Brotli => {
-                let mut decoder = BrotliDecoder::new(buf_read);
-                copy(&mut decoder, &mut write_file).await?;
-            }
-            Compression::None => {
-                copy_buf(&mut buf_read, &mut write_file).await?;
-            }
-            Compression::XzParallel => {
-                let mut decoder = XzDecoder::parallel_with_mem_limit(
-                    buf_read,
-                    NonZeroU32::new(num_cpus::get_physical() as u32 - 1).expect("0 cores? errr..."),
-                    256 * 1024 * 1024,
-                );
-                copy(&mut decoder, &mut write_file).await?;
-            }
-
-            Compression::Xz => {
-                let mut decoder = XzDecoder::with_mem_limit(buf_read, 256 * 1024 * 1024);
-                copy(&mut decoder, &mut write_file).await?;
+    pub async fn restore(self, destinations: NonEmpty<PathBuf>) -> anyhow::Result<RestoredFile> {
+        let mut restored = Vec::new();
+        let mut skipped = Vec::new();
+        for destination in destinations {
+            if Self::is_up_to_date(&destination, &self.data.original_hash).await? {
+                trace!("Output {:?} is already up to date", destination);
+                skipped.push(destination);
+            } else {
+                restored.push(destination);
             }
+        }
+        let Some(original_path) = restored.first() else {
+            return Ok(RestoredFile { restored, skipped });
         };
-        for dest in destinations.tail() {
+
+        for destination in &restored {
+            Self::create_parent(destination).await;
+        }
+
+        // the content is only verified once it is written, so outputs are replaced by a
+        // renamed temp file and never left half written or with unverified content
+        let temp_path = Self::temp_path(original_path);
+        let result = async {
+            let mut write_file = File::create(&temp_path).await.with_context(|| {
+                format!(
+                    "creating output file for cached file failed: {:?}",
+                    original_path
+                )
+            })?;
+            self.write_to(&mut write_file).await?;
+            write_file.flush().await?;
+            tokio::fs::rename(&temp_path, original_path).await?;
+            Ok(())
+        }
+        .await;
+        if result.is_err() {
+            let _ = tokio::fs::remove_file(&temp_path).await;
+        }
+        result?;
+        for dest in &restored[1..] {
+            let temp_path = Self::temp_path(dest);
             let source_file = File::open(original_path).await?;
-            let dest_file = File::create(dest).await?;
+            let dest_file = File::create(&temp_path).await?;
             let mut writer = BufWriter::new(dest_file);
             let mut reader = BufReader::new(source_file);
             copy_buf(&mut reader, &mut writer).await?;
+            writer.flush().await?;
+            tokio::fs::rename(&temp_path, dest).await?;
         }
 
-        Ok(destinations)
+        Ok(RestoredFile { restored, skipped })
     }
 
-    pub async fn restore_to_stdout(self) -> anyhow::Result<()> {
-        let read_file = File::open(&self.path).await?;
-        let mut buf_read = BufReader::new(read_file);
+    /// Hidden sibling of `destination`, renaming it in place stays on the same filesystem.
+    fn temp_path(destination: &Path) -> PathBuf {
+        let name = destination
+            .file_name()
+            .map(|name| name.to_string_lossy())
+            .unwrap_or_default();
+        destination.with_file_name(format!(".{}.{}.restoring", name, std::process::id()))
+    }
 
-        match &self.data.compression {
-            Compression::Brotli => {
-                let mut decoder = BrotliDecoder::new(buf_read);
-                copy(&mut decoder, &mut stdout()).await?;
-            }
-            Compression::None => {
-                copy_buf(&mut buf_read, &mut stdout()).await?;
-            }
-            Compression::XzParallel => {
-                let mut decoder = XzDecoder::parallel_with_mem_limit(
-                    buf_read,
-                    NonZeroU32::new(num_cpus::get_physical() as u32 - 1).expect("0 cores? errr..."),
-                    256 * 1024 * 1024,
-                );
-                copy(&mut decoder, &mut stdout()).await?;
-            }
-            Compression::Xz => {
-                let mut decoder = XzDecoder::with_mem_limit(buf_read, 256 * 1024 * 1024);
-                copy(&mut decoder, &mut stdout()).await?;
+    /// Reader over the stored bytes as they are, without decompressing them, and their length.
+    pub fn stored_reader(&self) -> anyhow::Result<(impl Read + use<>, u64)> {
+        let mut file = std::fs::File::open(&self.path)
+            .with_context(|| format!("failed to open cached file binary {:?}", &self.path))?;
+        let (offset, length) = match self.range {
+            Some(range) => range,
+            None => (0, file.metadata()?.len()),
+        };
+        file.seek(SeekFrom::Start(offset))?;
+        Ok((file.take(length), length))
+    }
+
+    /// An encrypted blob can't be read without a key, which isn't the blob's fault.
+    fn check_key(&self) -> anyhow::Result<()> {
+        if self.data.encrypted && installed_key().is_none() {
+            bail!(CacheError::MissingKey {
+                hash: self.hash.to_smolstr(),
+            });
+        }
+        Ok(())
+    }
+
+    /// Reads the stored blob without decompressing it, decrypting it if needed.
+    pub fn read_stored(&self) -> anyhow::Result<Vec<u8>> {
+        self.check_key()?;
+        let (mut reader, length) = self.stored_reader()?;
+        let mut bytes = Vec::with_capacity(length as usize);
+        reader.read_to_end(&mut bytes)?;
+        if self.data.encrypted {
+            return decrypt_bytes(&bytes, self.data.original_hash.as_bytes())
+                .map_err(|e| self.corrupt(e.to_string()).into());
+        }
+        Ok(bytes)
+    }
+
+    /// Chunks and dictionary this file needs to be restored.
+    pub fn referenced_blobs(&self) -> Vec<SmolStr> {
+        self.data
+            .chunks
+            .iter()
+            .flatten()
+            .chain(self.data.dictionary.iter())
+            .cloned()
+            .collect()
+    }
+
+    /// Hashes the original content by decompressing the stored blob.
+    pub async fn hash_content(&self) -> anyhow::Result<Hash> {
+        self.write_to(&mut sink()).await
+    }
+
+    fn corrupt(&self, reason: String) -> CacheError {
+        CacheError::CorruptBlob {
+            hash: self.hash.to_smolstr(),
+            reason,
+        }
+    }
+
+    fn verify(&self, hash: Hash) -> anyhow::Result<Hash> {
+        if hash != self.hash {
+            bail!(self.corrupt(format!("content hashes to {}", hash)));
+        }
+        Ok(hash)
+    }
+
+    async fn decompress_to<W: AsyncWrite + Unpin>(&self, writer: &mut W) -> anyhow::Result<Hash> {
+        self.check_key()?;
+        let mut read_file = File::open(&self.path)
+            .await
+            .map_err(|_| CacheError::OpenPathError(self.path.clone()))?;
+        let (offset, length) = self.range.unwrap_or((0, u64::MAX));
+        read_file.seek(SeekFrom::Start(offset)).await?;
+        let stored = read_file.take(length);
+        let mut writer = HashingWriter::new(writer);
+        let dictionary = self.dictionary.as_deref().map(|d| d.as_slice());
+        let result = if self.data.encrypted {
+            let aad = self.data.original_hash.as_bytes();
+            match DecryptingReader::new(stored, aad).await {
+                std::io::Result::Ok(reader) => {
+                    let reader = BufReader::new(reader);
+                    decompress(self.data.compression, dictionary, reader, &mut writer).await
+                }
+                Err(e) => Err(e),
             }
+        } else {
+            let reader = BufReader::new(stored);
+            decompress(self.data.compression, dictionary, reader, &mut writer).await
         };
+        result.map_err(|e| self.corrupt(format!("decompression failed: {}", e)))?;
+        self.verify(writer.hasher.finalize())
+    }
+
+    /// Writes the original content, reassembling it from its chunks if the file is stored chunked.
+    /// Fails with [`CacheError::CorruptBlob`] if the written content does not match its hash.
+    pub async fn write_to<W: AsyncWrite + Unpin>(&self, writer: &mut W) -> anyhow::Result<Hash> {
+        if self.data.chunks.is_none() {
+            return self.decompress_to(writer).await;
+        }
+        let mut writer = HashingWriter::new(writer);
+        for chunk in &self.chunks {
+            chunk.decompress_to(&mut writer).await?;
+        }
+        self.verify(writer.hasher.finalize())
+    }
 
+    /// Verifies the content before printing it, a corrupt log is never printed.
+    pub async fn restore_to_stdout(self) -> anyhow::Result<()> {
+        self.hash_content().await?;
+        self.write_to(&mut stdout()).await?;
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cache::test_util::store_blob;
+
+    fn write_chunked(cache_root: &Path, hash: &Hash, chunks: &[&Hash]) {
+        let dir = cache_root.join(FILE_FOLDER_NAME).join(hash.to_string());
+        std::fs::create_dir_all(&dir).unwrap();
+        let data = StoredCacheFile {
+            version: FORMAT_VERSION,
+            created: Utc::now(),
+            original_hash: hash.to_smolstr(),
+            compression: Compression::None,
+            dictionary: None,
+            chunks: Some(chunks.iter().map(|c| c.to_smolstr()).collect()),
+            encrypted: false,
+        };
+        std::fs::write(
+            dir.join(DATA_FILE_NAME),
+            serde_json::to_string(&data).unwrap(),
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn record_listing_itself_as_chunk_is_corrupt() {
+        let dir = tempfile::tempdir().unwrap();
+        let hash = blake3::hash(b"parent");
+        write_chunked(dir.path(), &hash, &[&hash]);
+
+        let error = CachedFile::open(dir.path(), &hash).unwrap_err();
+        assert!(error.to_string().contains("lists itself"), "{}", error);
+    }
+
+    #[test]
+    fn chunked_chunks_are_corrupt() {
+        let dir = tempfile::tempdir().unwrap();
+        let a = blake3::hash(b"a");
+        let b = blake3::hash(b"b");
+        write_chunked(dir.path(), &a, &[&b]);
+        write_chunked(dir.path(), &b, &[&a]);
+
+        let error = CachedFile::open(dir.path(), &a).unwrap_err();
+        assert!(error.to_string().contains("split into chunks"), "{}", error);
+    }
+
+    #[test]
+    fn dictionary_does_not_override_configured_compression() {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        std::io::Write::write_all(&mut file, "a line of text\n".repeat(2000).as_bytes()).unwrap();
+        let size = file.as_file().metadata().unwrap().len();
+        let compression = |default_compression: Option<Compression>| {
+            let policy = CompressionPolicy {
+                default_compression: default_compression.map(Into::into),
+                dictionary: Some(Dictionary {
+                    hash: blake3::hash(b"dictionary").to_smolstr(),
+                    data: Arc::new(vec![]),
+                }),
+                ..Default::default()
+            };
+            CachedFile::determine_compression(file.path(), size, &policy)
+                .unwrap()
+                .compression
+        };
+        assert_eq!(compression(None), Compression::ZstdDictionary);
+        assert_eq!(
+            compression(Some(Compression::Zstd)),
+            Compression::ZstdDictionary
+        );
+        assert_eq!(compression(Some(Compression::Brotli)), Compression::Brotli);
+        assert_eq!(compression(Some(Compression::None)), Compression::None);
+    }
+
+    #[tokio::test]
+    async fn restore_skips_up_to_date_outputs() {
+        let cache = tempfile::tempdir().unwrap();
+        let work = tempfile::tempdir().unwrap();
+        let hash = store_blob(cache.path(), b"cached content").await;
+
+        let same = work.path().join("same");
+        let changed = work.path().join("changed");
+        let missing = work.path().join("out/missing");
+        std::fs::write(&same, b"cached content").unwrap();
+        std::fs::write(&changed, b"edited content").unwrap();
+        let modified = std::fs::metadata(&same).unwrap().modified().unwrap();
+
+        let file = CachedFile::open(cache.path(), &hash).unwrap();
+        let destinations = NonEmpty::from((same.clone(), vec![changed.clone(), missing.clone()]));
+        let result = file.restore(destinations).await.unwrap();
+
+        assert_eq!(result.skipped, vec![same.clone()]);
+        assert_eq!(result.restored, vec![changed.clone(), missing.clone()]);
+        assert_eq!(std::fs::read(&changed).unwrap(), b"cached content");
+        assert_eq!(std::fs::read(&missing).unwrap(), b"cached content");
+        assert_eq!(
+            std::fs::metadata(&same).unwrap().modified().unwrap(),
+            modified
+        );
+    }
+
+    #[tokio::test]
+    async fn too_little_savings_store_uncompressed() {
+        let cache = tempfile::tempdir().unwrap();
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        std::io::Write::write_all(&mut file, "a line of text\n".repeat(2000).as_bytes()).unwrap();
+        let (hash, size) = CachedFile::hash_path(file.path()).unwrap();
+
+        let store = |min_savings_percent| {
+            let policy = Arc::new(CompressionPolicy {
+                default_compression: Some(Compression::Zstd.into()),
+                min_savings_percent,
+                ..Default::default()
+            });
+            let cache_root = cache.path().to_owned();
+            let path = file.path().to_owned();
+            async move {
+                let _ = std::fs::remove_dir_all(cache_root.join(FILE_FOLDER_NAME));
+                CachedFile::create(cache_root.clone(), path, hash, size, policy)
+                    .await
+                    .unwrap();
+                let stored = CachedFile::open(&cache_root, &hash).unwrap();
+                assert_eq!(stored.hash_content().await.unwrap(), hash);
+                stored.data.compression
+            }
+        };
+        assert_eq!(store(Some(50)).await, Compression::Zstd);
+        assert_eq!(store(Some(100)).await, Compression::None);
+    }
+}
@@ -1,27 +1,66 @@
-use std::{
-    fs::{self, File},
-    path::PathBuf,
-};
+use std::{collections::BTreeMap, fs, path::PathBuf, sync::OnceLock};
 
-use anyhow::bail;
+use anyhow::{Context, bail};
+use itertools::Itertools;
+use smol_str::SmolStr;
+use tracing::debug;
 
 use crate::cache::{
     command::{COMMAND_DIR, COMMAND_FILE_NAME, CachedCommand},
     file::CachedFile,
+    format::is_unknown_version,
+    pack::remove_from_pack,
 };
 
-pub const FILE_FOLDER_NAME: &'static str = "files";
+pub const FILE_FOLDER_NAME: &str = "files";
 pub struct CacheFolder {
     pub root: PathBuf,
+    /// See [`Self::plain_command_lines`].
+    plain_commands: OnceLock<BTreeMap<SmolStr, Vec<SmolStr>>>,
 }
 
 impl CacheFolder {
     pub fn new(root: impl Into<PathBuf>) -> Self {
-        Self { root: root.into() }
+        Self {
+            root: root.into(),
+            plain_commands: OnceLock::new(),
+        }
+    }
+
+    /// Command lines cached for a project without a task, newest first. The records are only
+    /// read for the first call, later ones use that index.
+    pub fn plain_command_lines(&self, project: &str) -> anyhow::Result<Vec<SmolStr>> {
+        if self.plain_commands.get().is_none() {
+            let mut commands = self.readable_commands()?;
+            commands.retain(|c| c.project.is_some() && c.task.is_none());
+            commands.sort_by_key(|c| std::cmp::Reverse(c.created));
+            let mut index: BTreeMap<SmolStr, Vec<SmolStr>> = BTreeMap::new();
+            for command in commands {
+                let project = command.project.expect("only commands of projects are kept");
+                index.entry(project).or_default().push(command.command_line);
+            }
+            for command_lines in index.values_mut() {
+                *command_lines = command_lines.drain(..).unique().collect();
+            }
+            _ = self.plain_commands.set(index);
+        }
+        let index = self.plain_commands.get().expect("index was just built");
+        Ok(index.get(project).cloned().unwrap_or_default())
     }
 
     pub fn has_cached_file(&self, hash: &blake3::Hash) -> bool {
-        fs::exists(self.root.join(FILE_FOLDER_NAME).join(&hash.to_string())).is_ok()
+        CachedFile::is_stored(&self.root, hash).unwrap_or(false)
+    }
+
+    pub fn remove_cached_file(&self, hash: &blake3::Hash) -> anyhow::Result<()> {
+        let file_dir = self.root.join(FILE_FOLDER_NAME).join(hash.to_string());
+        if file_dir.exists() {
+            fs::remove_dir_all(&file_dir)
+                .with_context(|| format!("Could not remove {:?}", file_dir))?;
+        } else {
+            remove_from_pack(&self.root, hash)?;
+        }
+        Ok(())
     }
 
     pub async fn get_cached_file(&self, hash: &blake3::Hash) -> anyhow::Result<CachedFile> {
@@ -29,10 +68,45 @@ impl CacheFolder {
     }
 
     pub fn has_cached_command(&self, hash: &blake3::Hash) -> bool {
-        let exists = fs::exists(self.root.join(COMMAND_DIR).join(&hash.to_string()));
+        let exists = fs::exists(self.root.join(COMMAND_DIR).join(hash.to_string()));
         exists.expect("Could not find cache file")
     }
 
+    pub fn remove_cached_command(&self, hash: &blake3::Hash) -> anyhow::Result<()> {
+        let command_folder = self.root.join(COMMAND_DIR).join(hash.to_string());
+        if command_folder.exists() {
+            fs::remove_dir_all(&command_folder)
+                .with_context(|| format!("Could not remove {:?}", command_folder))?;
+        }
+        Ok(())
+    }
+
+    /// Removes a corrupt blob together with the command that referenced it, chunked outputs
+    /// containing the blob are removed as well so they are stored again on the next run.
+    pub fn discard_corrupt(
+        &self,
+        command_hash: &blake3::Hash,
+        blob_hash: &blake3::Hash,
+    ) -> anyhow::Result<()> {
+        let command = self.get_cashed_command(command_hash)?;
+        let blob = blob_hash.to_string();
+        for hash in command.referenced_blobs() {
+            let hash = blake3::Hash::from_hex(hash.as_bytes())?;
+            let contains_blob = CachedFile::open(&self.root, &hash).is_ok_and(|file| {
+                file.data
+                    .chunks
+                    .iter()
+                    .flatten()
+                    .any(|chunk| *chunk == blob)
+            });
+            if contains_blob {
+                self.remove_cached_file(&hash)?;
+            }
+        }
+        self.remove_cached_file(blob_hash)?;
+        self.remove_cached_command(command_hash)
+    }
+
     pub fn get_cashed_command(
         &self,
         hash: &blake3::Hash,
@@ -51,7 +125,40 @@ impl CacheFolder {
             );
         }
 
-        let command: CachedCommand = serde_json::from_reader(File::open(json_file)?)?;
-        Ok(command)
+        CachedCommand::load(&json_file, &hash.to_string())
+    }
+
+    pub fn list_commands(&self) -> anyhow::Result<Vec<CachedCommand>> {
+        self.load_commands(false)
+    }
+
+    /// Like [`Self::list_commands`], but records that can't be read are skipped.
+    pub fn readable_commands(&self) -> anyhow::Result<Vec<CachedCommand>> {
+        self.load_commands(true)
+    }
+
+    fn load_commands(&self, skip_unreadable: bool) -> anyhow::Result<Vec<CachedCommand>> {
+        let command_dir = self.root.join(COMMAND_DIR);
+        if !command_dir.exists() {
+            return Ok(vec![]);
+        }
+        let mut commands = Vec::new();
+        for entry in fs::read_dir(&command_dir)? {
+            let entry = entry?;
+            let json_file = entry.path().join(COMMAND_FILE_NAME);
+            if !json_file.exists() {
+                debug!("Skipping incomplete command {:?}", entry.path());
+                continue;
+            }
+            match CachedCommand::load(&json_file, &entry.file_name().to_string_lossy()) {
+                Ok(command) => commands.push(command),
+                Err(e) if is_unknown_version(&e) => debug!("Skipping {:#}", e),
+                Err(e) if skip_unreadable => {
+                    debug!("Skipping unreadable command {:?}: {:#}", entry.path(), e)
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(commands)
     }
 }
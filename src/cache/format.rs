@@ -0,0 +1,294 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, bail};
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
+use tracing::{debug, info, warn};
+
+use crate::{
+    cache::{
+        command::{COMMAND_DIR, COMMAND_FILE_NAME},
+        file::DATA_FILE_NAME,
+        folder::FILE_FOLDER_NAME,
+        pack::{PackIndex, PackIndexFile, pack_files},
+    },
+    error::CacheError,
+};
+
+/// Version of the cache folder layout and its records, records without a version are version 0.
+/// Since version 2 command records can be sealed and blobs encrypted.
+pub const FORMAT_VERSION: u32 = 2;
+pub const FORMAT_FILE_NAME: &str = "format.json";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CacheFormat {
+    pub version: u32,
+}
+
+#[derive(Deserialize)]
+struct Versioned {
+    #[serde(default)]
+    version: u32,
+}
+
+/// Parses a record, records written by a newer version fail with [`CacheError::UnknownVersion`]
+/// before their content is interpreted.
+pub fn read_record<T: DeserializeOwned>(path: &Path) -> anyhow::Result<T> {
+    let bytes = fs::read(path).map_err(|_| CacheError::OpenPathError(path.to_owned()))?;
+    let Versioned { version } =
+        serde_json::from_slice(&bytes).with_context(|| format!("Could not parse {:?}", path))?;
+    if version > FORMAT_VERSION {
+        bail!(CacheError::UnknownVersion {
+            path: path.to_owned(),
+            version,
+        });
+    }
+    serde_json::from_slice(&bytes).with_context(|| format!("Could not parse {:?}", path))
+}
+
+pub fn is_unknown_version(error: &anyhow::Error) -> bool {
+    matches!(
+        error.downcast_ref::<CacheError>(),
+        Some(CacheError::UnknownVersion { .. })
+    )
+}
+
+fn write_record<T: Serialize>(path: &Path, record: &T) -> anyhow::Result<()> {
+    fs::write(path, serde_json::to_string(record)?)
+        .with_context(|| format!("Could not write {:?}", path))
+}
+
+impl CacheFormat {
+    fn path(cache_root: &Path) -> PathBuf {
+        cache_root.join(FORMAT_FILE_NAME)
+    }
+
+    /// Version of the cache folder, a folder with records but without `format.json` predates
+    /// versioning and is version 0.
+    pub fn read(cache_root: &Path) -> anyhow::Result<u32> {
+        let path = Self::path(cache_root);
+        if path.exists() {
+            let format: CacheFormat = serde_json::from_slice(&fs::read(&path)?)
+                .with_context(|| format!("Could not parse {:?}", path))?;
+            Ok(format.version)
+        } else if cache_root.join(FILE_FOLDER_NAME).exists()
+            || cache_root.join(COMMAND_DIR).exists()
+        {
+            Ok(0)
+        } else {
+            Ok(FORMAT_VERSION)
+        }
+    }
+
+    fn write(cache_root: &Path, version: u32) -> anyhow::Result<()> {
+        fs::create_dir_all(cache_root)?;
+        write_record(&Self::path(cache_root), &CacheFormat { version })
+    }
+
+    /// Marks new cache folders with the current version and warns about outdated or newer ones.
+    pub fn check(cache_root: &Path) -> anyhow::Result<()> {
+        let version = Self::read(cache_root)?;
+        if version < FORMAT_VERSION {
+            warn!(
+                "Cache folder {:?} uses format version {}, run migrate to upgrade it to {}",
+                cache_root, version, FORMAT_VERSION
+            );
+        } else if version > FORMAT_VERSION {
+            warn!(
+                "Cache folder {:?} was written with format version {}, records newer than {} are treated as misses",
+                cache_root, version, FORMAT_VERSION
+            );
+        } else if !Self::path(cache_root).exists() {
+            Self::write(cache_root, FORMAT_VERSION)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct MigrateSummary {
+    pub from: u32,
+    pub to: u32,
+    pub files: usize,
+    pub commands: usize,
+    pub packs: usize,
+}
+
+fn record_paths(dir: &Path, file_name: &str) -> anyhow::Result<Vec<PathBuf>> {
+    if !dir.exists() {
+        return Ok(vec![]);
+    }
+    let mut paths = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path().join(file_name);
+        if path.exists() {
+            paths.push(path);
+        }
+    }
+    Ok(paths)
+}
+
+/// Sets the version of every record, for steps where the content of the records stays the
+/// same. Records are rewritten as JSON values, so sealed commands keep their content.
+fn rewrite_versions(
+    cache_root: &Path,
+    version: u32,
+    summary: &mut MigrateSummary,
+) -> anyhow::Result<()> {
+    let records = [
+        (FILE_FOLDER_NAME, DATA_FILE_NAME),
+        (COMMAND_DIR, COMMAND_FILE_NAME),
+    ];
+    for (folder, file_name) in records {
+        for path in record_paths(&cache_root.join(folder), file_name)? {
+            let mut record: serde_json::Value = read_record(&path)?;
+            let Some(fields) = record.as_object_mut() else {
+                bail!("{:?} is not a record", path);
+            };
+            fields.insert("version".into(), version.into());
+            write_record(&path, &record)?;
+            if folder == FILE_FOLDER_NAME {
+                summary.files += 1;
+            } else {
+                summary.commands += 1;
+            }
+        }
+    }
+    for (_, index_path) in pack_files(cache_root)? {
+        let mut index: PackIndexFile = read_record(&index_path)?;
+        index.version = version;
+        for entry in index.blobs.values_mut() {
+            entry.file.version = version;
+        }
+        write_record(&index_path, &index)?;
+        summary.packs += 1;
+    }
+    Ok(())
+}
+
+/// Upgrades the cache folder in place one version at a time, `format.json` is written after
+/// each step so an interrupted migration continues where it stopped.
+pub fn migrate(cache_root: &Path) -> anyhow::Result<MigrateSummary> {
+    let from = CacheFormat::read(cache_root)?;
+    if from > FORMAT_VERSION {
+        bail!(
+            "Cache folder {:?} uses format version {}, this build only knows up to {}",
+            cache_root,
+            from,
+            FORMAT_VERSION
+        );
+    }
+    let mut summary = MigrateSummary {
+        from,
+        to: from,
+        ..Default::default()
+    };
+    while summary.to < FORMAT_VERSION {
+        info!(
+            "Migrating cache folder {:?} from format version {} to {}",
+            cache_root,
+            summary.to,
+            summary.to + 1
+        );
+        match summary.to {
+            // version 0 records only lack their version
+            0 => rewrite_versions(cache_root, 1, &mut summary)?,
+            // version 1 records are never encrypted, version 2 ones are readable as they are
+            1 => rewrite_versions(cache_root, 2, &mut summary)?,
+            version => bail!("No migration from format version {}", version),
+        }
+        summary.to += 1;
+        CacheFormat::write(cache_root, summary.to)?;
+    }
+    if !CacheFormat::path(cache_root).exists() {
+        CacheFormat::write(cache_root, FORMAT_VERSION)?;
+    }
+    PackIndex::invalidate(cache_root);
+    debug!(
+        "Cache folder {:?} is at format version {}",
+        cache_root, summary.to
+    );
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Utc;
+
+    use super::*;
+    use crate::cache::{compression::Compression, file::StoredCacheFile};
+
+    fn write_json(path: &Path, value: serde_json::Value) {
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, value.to_string()).unwrap();
+    }
+
+    fn file_record(version: u32) -> serde_json::Value {
+        serde_json::to_value(StoredCacheFile {
+            version,
+            created: Utc::now(),
+            original_hash: blake3::hash(b"content").to_string().into(),
+            compression: Compression::None,
+            dictionary: None,
+            chunks: None,
+            encrypted: false,
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn check_keeps_a_newer_format() {
+        let dir = tempfile::tempdir().unwrap();
+        CacheFormat::check(dir.path()).unwrap();
+        assert_eq!(CacheFormat::read(dir.path()).unwrap(), FORMAT_VERSION);
+
+        CacheFormat::write(dir.path(), FORMAT_VERSION + 1).unwrap();
+        CacheFormat::check(dir.path()).unwrap();
+        assert_eq!(CacheFormat::read(dir.path()).unwrap(), FORMAT_VERSION + 1);
+        let error = migrate(dir.path()).unwrap_err();
+        assert!(error.to_string().contains("only knows up to"), "{}", error);
+    }
+
+    #[test]
+    fn newer_records_are_unknown_versions() {
+        let dir = tempfile::tempdir().unwrap();
+        let newer = dir.path().join("newer.json");
+        write_json(&newer, file_record(FORMAT_VERSION + 1));
+        let error = read_record::<StoredCacheFile>(&newer).unwrap_err();
+        assert!(is_unknown_version(&error), "{}", error);
+
+        let current = dir.path().join("current.json");
+        write_json(&current, file_record(FORMAT_VERSION));
+        assert!(read_record::<StoredCacheFile>(&current).is_ok());
+
+        let broken = dir.path().join("broken.json");
+        write_json(&broken, serde_json::json!({ "version": 1 }));
+        let error = read_record::<StoredCacheFile>(&broken).unwrap_err();
+        assert!(!is_unknown_version(&error), "{}", error);
+    }
+
+    #[test]
+    fn migrate_upgrades_unversioned_records() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut record = file_record(0);
+        record.as_object_mut().unwrap().remove("version");
+        let path = dir
+            .path()
+            .join(FILE_FOLDER_NAME)
+            .join("blob")
+            .join(DATA_FILE_NAME);
+        write_json(&path, record);
+        assert_eq!(CacheFormat::read(dir.path()).unwrap(), 0);
+
+        let summary = migrate(dir.path()).unwrap();
+        assert_eq!((summary.from, summary.to), (0, FORMAT_VERSION));
+        assert_eq!(CacheFormat::read(dir.path()).unwrap(), FORMAT_VERSION);
+        let migrated: StoredCacheFile = read_record(&path).unwrap();
+        assert_eq!(migrated.version, FORMAT_VERSION);
+
+        let summary = migrate(dir.path()).unwrap();
+        assert_eq!((summary.from, summary.files), (FORMAT_VERSION, 0));
+    }
+}
@@ -6,7 +6,7 @@ use smol_str::SmolStr;
 use tracing::{error, trace};
 
 pub fn get_paths_from_globs(glob_strings: &[SmolStr], root_dir: &Path) -> Vec<PathBuf> {
-    let paths = glob_strings
+    glob_strings
         .iter()
         .filter_map(|pattern| {
             let full_pattern = format!(
@@ -40,6 +40,5 @@ pub fn get_paths_from_globs(glob_strings: &[SmolStr], root_dir: &Path) -> Vec<Pa
         })
         .filter(|p| p.is_file())
         .unique()
-        .collect();
-    paths
+        .collect()
 }
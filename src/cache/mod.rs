@@ -1,4 +1,16 @@
+pub mod archive;
+pub mod chunk;
 pub mod command;
+pub mod compression;
+pub mod dictionary;
+pub mod encryption;
 pub mod file;
 pub mod folder;
+pub mod format;
 pub mod glob;
+pub mod pack;
+pub mod signing;
+pub mod sniff;
+#[cfg(test)]
+pub(crate) mod test_util;
+pub mod verify;
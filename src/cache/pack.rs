@@ -0,0 +1,325 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs,
+    path::{Path, PathBuf},
+    sync::{Arc, LazyLock, Mutex},
+};
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use smol_str::{SmolStr, ToSmolStr};
+use tokio::io::{AsyncWriteExt, BufWriter};
+use tracing::{debug, info, warn};
+
+use crate::cache::{
+    file::{COMPRESSED_FILE_NAME, DATA_FILE_NAME, StoredCacheFile},
+    folder::FILE_FOLDER_NAME,
+    format::{FORMAT_VERSION, is_unknown_version, read_record},
+};
+
+pub const PACK_DIR: &str = "packs";
+pub const PACK_EXTENSION: &str = "pack";
+pub const PACK_INDEX_EXTENSION: &str = "json";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PackEntry {
+    pub offset: u64,
+    pub length: u64,
+    pub file: StoredCacheFile,
+}
+
+/// Index stored next to each pack file, `blobs` maps the blob hash to its location in the pack.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PackIndexFile {
+    #[serde(default)]
+    pub version: u32,
+    pub created: DateTime<Utc>,
+    pub blobs: BTreeMap<SmolStr, PackEntry>,
+}
+
+#[derive(Debug, Clone)]
+pub struct PackedBlob {
+    pub pack: PathBuf,
+    pub offset: u64,
+    pub length: u64,
+    pub data: StoredCacheFile,
+}
+
+/// All pack indexes of one cache folder combined.
+#[derive(Debug, Default)]
+pub struct PackIndex {
+    blobs: HashMap<SmolStr, PackedBlob>,
+}
+
+static PACK_INDEXES: LazyLock<Mutex<HashMap<PathBuf, Arc<PackIndex>>>> =
+    LazyLock::new(Default::default);
+
+impl PackIndex {
+    /// Reads all pack indexes once per process and cache folder.
+    pub fn load(cache_root: &Path) -> anyhow::Result<Arc<PackIndex>> {
+        let mut indexes = PACK_INDEXES.lock().expect("pack index lock poisoned");
+        if let Some(index) = indexes.get(cache_root) {
+            return Ok(index.clone());
+        }
+        let index = Arc::new(Self::read(cache_root)?);
+        indexes.insert(cache_root.to_owned(), index.clone());
+        Ok(index)
+    }
+
+    /// Forgets the loaded indexes after packs were written or removed.
+    pub fn invalidate(cache_root: &Path) {
+        PACK_INDEXES
+            .lock()
+            .expect("pack index lock poisoned")
+            .remove(cache_root);
+    }
+
+    fn read(cache_root: &Path) -> anyhow::Result<PackIndex> {
+        let mut blobs = HashMap::new();
+        for (pack, index_path) in pack_files(cache_root)? {
+            let index: PackIndexFile = match read_record(&index_path) {
+                Err(e) if is_unknown_version(&e) => {
+                    debug!("Skipping {:#}", e);
+                    continue;
+                }
+                index => index?,
+            };
+            debug!("Loaded {} blobs from {:?}", index.blobs.len(), index_path);
+            for (hash, entry) in index.blobs {
+                if entry.file.version > FORMAT_VERSION {
+                    debug!("Skipping blob {} with newer format version", hash);
+                    continue;
+                }
+                blobs.insert(
+                    hash,
+                    PackedBlob {
+                        pack: pack.clone(),
+                        offset: entry.offset,
+                        length: entry.length,
+                        data: entry.file,
+                    },
+                );
+            }
+        }
+        Ok(PackIndex { blobs })
+    }
+
+    pub fn get(&self, hash: &blake3::Hash) -> Option<&PackedBlob> {
+        self.blobs.get(&hash.to_smolstr())
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&SmolStr, &PackedBlob)> {
+        self.blobs.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.blobs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.blobs.is_empty()
+    }
+}
+
+/// Pairs of pack file and index file, packs without an index are ignored.
+pub fn pack_files(cache_root: &Path) -> anyhow::Result<Vec<(PathBuf, PathBuf)>> {
+    let pack_dir = cache_root.join(PACK_DIR);
+    if !pack_dir.exists() {
+        return Ok(vec![]);
+    }
+    let mut result = Vec::new();
+    for entry in fs::read_dir(&pack_dir)? {
+        let path = entry?.path();
+        if path.extension().is_some_and(|e| e == PACK_EXTENSION) {
+            let index_path = path.with_extension(PACK_INDEX_EXTENSION);
+            if index_path.exists() {
+                result.push((path, index_path));
+            } else {
+                warn!("Ignoring pack {:?} without index", path);
+            }
+        }
+    }
+    result.sort();
+    Ok(result)
+}
+
+/// Drops a blob from the index of its pack, its bytes stay in the pack file.
+pub fn remove_from_pack(cache_root: &Path, hash: &blake3::Hash) -> anyhow::Result<bool> {
+    let Some(packed) = PackIndex::load(cache_root)?.get(hash).cloned() else {
+        return Ok(false);
+    };
+    let index_path = packed.pack.with_extension(PACK_INDEX_EXTENSION);
+    let mut index: PackIndexFile = read_record(&index_path)?;
+    index.blobs.remove(&hash.to_smolstr());
+    fs::write(&index_path, serde_json::to_string(&index)?)?;
+    PackIndex::invalidate(cache_root);
+    Ok(true)
+}
+
+#[derive(Debug, Default)]
+pub struct RepackSummary {
+    pub packs: usize,
+    pub blobs: usize,
+    pub bytes: u64,
+}
+
+struct LooseBlob {
+    dir: PathBuf,
+    hash: SmolStr,
+    data: StoredCacheFile,
+    length: u64,
+}
+
+fn loose_blobs(cache_root: &Path, max_blob_size: u64) -> anyhow::Result<Vec<LooseBlob>> {
+    let files_dir = cache_root.join(FILE_FOLDER_NAME);
+    if !files_dir.exists() {
+        return Ok(vec![]);
+    }
+    let mut blobs = Vec::new();
+    for entry in fs::read_dir(&files_dir)? {
+        let dir = entry?.path();
+        let json_path = dir.join(DATA_FILE_NAME);
+        let binary_path = dir.join(COMPRESSED_FILE_NAME);
+        let Some(hash) = dir.file_name().map(|n| n.to_string_lossy().to_smolstr()) else {
+            continue;
+        };
+        if !json_path.exists() {
+            debug!("Skipping incomplete blob {:?}", dir);
+            continue;
+        }
+        let data: StoredCacheFile = match read_record(&json_path) {
+            Err(e) if is_unknown_version(&e) => {
+                debug!("Skipping {:#}", e);
+                continue;
+            }
+            data => data?,
+        };
+        let length = match (&data.chunks, binary_path.metadata()) {
+            (Some(_), _) => 0,
+            (None, Ok(metadata)) => metadata.len(),
+            (None, Err(_)) => {
+                debug!("Skipping incomplete blob {:?}", dir);
+                continue;
+            }
+        };
+        if length <= max_blob_size {
+            blobs.push(LooseBlob {
+                dir,
+                hash,
+                data,
+                length,
+            });
+        }
+    }
+    blobs.sort_by(|a, b| a.hash.cmp(&b.hash));
+    Ok(blobs)
+}
+
+async fn write_pack(cache_root: &Path, blobs: &[LooseBlob]) -> anyhow::Result<u64> {
+    let pack_dir = cache_root.join(PACK_DIR);
+    tokio::fs::create_dir_all(&pack_dir).await?;
+    let temp_path = pack_dir.join(format!("{}.tmp", blobs[0].hash));
+    let mut writer = BufWriter::new(tokio::fs::File::create(&temp_path).await?);
+    let mut hasher = blake3::Hasher::new();
+    let mut index = PackIndexFile {
+        version: FORMAT_VERSION,
+        created: Utc::now(),
+        blobs: BTreeMap::new(),
+    };
+    let mut offset = 0;
+    for blob in blobs {
+        let bytes = match blob.data.chunks {
+            Some(_) => vec![],
+            None => tokio::fs::read(blob.dir.join(COMPRESSED_FILE_NAME)).await?,
+        };
+        hasher.update(&bytes);
+        writer.write_all(&bytes).await?;
+        index.blobs.insert(
+            blob.hash.clone(),
+            PackEntry {
+                offset,
+                length: bytes.len() as u64,
+                file: blob.data.clone(),
+            },
+        );
+        offset += bytes.len() as u64;
+    }
+    writer.flush().await?;
+
+    let name = hasher.finalize().to_string();
+    let pack_path = pack_dir.join(format!("{}.{}", name, PACK_EXTENSION));
+    tokio::fs::rename(&temp_path, &pack_path).await?;
+    let index_path = pack_path.with_extension(PACK_INDEX_EXTENSION);
+    tokio::fs::write(&index_path, serde_json::to_string(&index)?).await?;
+    info!(
+        "Wrote pack {:?} with {} blobs ({} bytes)",
+        pack_path,
+        blobs.len(),
+        offset
+    );
+    Ok(offset)
+}
+
+/// Moves loose blobs of at most `max_blob_size` bytes into packs of roughly `max_pack_size` bytes.
+/// The loose directories are only removed after the pack and its index were written.
+pub async fn repack(
+    cache_root: &Path,
+    max_blob_size: u64,
+    max_pack_size: u64,
+) -> anyhow::Result<RepackSummary> {
+    let blobs = loose_blobs(cache_root, max_blob_size)?;
+    let mut summary = RepackSummary::default();
+    let mut start = 0;
+    while start < blobs.len() {
+        let mut end = start;
+        let mut size = 0;
+        while end < blobs.len() && (end == start || size + blobs[end].length <= max_pack_size) {
+            size += blobs[end].length;
+            end += 1;
+        }
+        let pack = &blobs[start..end];
+        summary.bytes += write_pack(cache_root, pack).await?;
+        summary.packs += 1;
+        summary.blobs += pack.len();
+        for blob in pack {
+            tokio::fs::remove_dir_all(&blob.dir).await?;
+        }
+        start = end;
+    }
+    PackIndex::invalidate(cache_root);
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cache::{file::CachedFile, test_util::store_blob};
+
+    #[tokio::test]
+    async fn packed_blobs_can_still_be_read() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache_root = dir.path();
+        let mut small = Vec::new();
+        for content in ["first", "second", "third"] {
+            small.push(store_blob(cache_root, content.repeat(10).as_bytes()).await);
+        }
+        let large = store_blob(cache_root, &[7; 4096]).await;
+
+        let summary = repack(cache_root, 1024, 100).await.unwrap();
+        assert_eq!((summary.packs, summary.blobs), (2, 3));
+        assert_eq!(pack_files(cache_root).unwrap().len(), 2);
+        assert_eq!(PackIndex::load(cache_root).unwrap().len(), 3);
+        for hash in small.iter().chain([&large]) {
+            let file = CachedFile::open(cache_root, hash).unwrap();
+            assert_eq!(file.hash_content().await.unwrap(), *hash);
+        }
+        let loose = cache_root.join(FILE_FOLDER_NAME);
+        assert!(!loose.join(small[0].to_string()).exists());
+        assert!(loose.join(large.to_string()).exists());
+
+        assert!(remove_from_pack(cache_root, &small[0]).unwrap());
+        assert!(!remove_from_pack(cache_root, &small[0]).unwrap());
+        assert!(CachedFile::open(cache_root, &small[0]).is_err());
+        assert!(CachedFile::open(cache_root, &small[1]).is_ok());
+    }
+}
@@ -0,0 +1,213 @@
+use std::{
+    env, fs,
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, anyhow, bail};
+use ed25519_dalek::{SECRET_KEY_LENGTH, Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use smol_str::{SmolStr, ToSmolStr};
+use tracing::warn;
+
+use crate::{cache::command::CachedCommand, error::CacheError};
+
+/// Hex encoded secret key, takes precedence over `key_file`. It is read here instead of in
+/// the env config so the secret never ends up in logged configuration.
+pub const SIGNING_KEY_ENV: &str = "CACHE_SIGNING_KEY";
+const SIGNATURE_CONTEXT: &str = "simple-ci-cache 2025 command signature v1";
+
+/// With `trusted_keys` only command records signed by one of these keys are restored,
+/// with a key new command records are signed.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, JsonSchema)]
+#[serde(default, deny_unknown_fields)]
+pub struct SigningConfig {
+    /// Hex encoded ed25519 public keys.
+    #[schemars(with = "Vec<String>")]
+    pub trusted_keys: Vec<SmolStr>,
+    /// File containing the hex encoded secret key, relative to the config file.
+    pub key_file: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CommandSignature {
+    pub key: SmolStr,
+    pub signature: SmolStr,
+}
+
+fn parse_hex<const N: usize>(value: &str) -> anyhow::Result<[u8; N]> {
+    let bytes = hex::decode(value.trim())?;
+    bytes
+        .try_into()
+        .map_err(|bytes: Vec<u8>| anyhow!("expected {} bytes, got {}", N, bytes.len()))
+}
+
+/// Digest of everything a restore acts on: the command key, the log and every output with
+/// the paths it is restored to.
+fn signed_digest(command: &CachedCommand) -> blake3::Hash {
+    let mut hasher = blake3::Hasher::new_derive_key(SIGNATURE_CONTEXT);
+    let mut update = |value: &str| {
+        hasher.update(&(value.len() as u64).to_le_bytes());
+        hasher.update(value.as_bytes());
+    };
+    update(&command.hash);
+    update(&command.log);
+    for output in &command.output_files {
+        update(&output.hash);
+        update(&output.paths.len().to_string());
+        output.paths.iter().for_each(|path| update(path));
+    }
+    hasher.finalize()
+}
+
+impl SigningConfig {
+    pub fn signing_key(&self, root: &Path) -> anyhow::Result<Option<SigningKey>> {
+        let secret = match (env::var(SIGNING_KEY_ENV), &self.key_file) {
+            (Ok(secret), _) => secret,
+            (Err(_), Some(key_file)) => {
+                let path = root.join(key_file);
+                if !path.exists() {
+                    warn!(
+                        "Signing key {:?} does not exist, command records stay unsigned",
+                        path
+                    );
+                    return Ok(None);
+                }
+                fs::read_to_string(&path)
+                    .with_context(|| format!("Could not read signing key {:?}", path))?
+            }
+            (Err(_), None) => return Ok(None),
+        };
+        let secret = parse_hex::<SECRET_KEY_LENGTH>(&secret).context("Invalid signing key")?;
+        Ok(Some(SigningKey::from_bytes(&secret)))
+    }
+
+    pub fn trusted_keys(&self) -> anyhow::Result<Vec<VerifyingKey>> {
+        self.trusted_keys
+            .iter()
+            .map(|key| {
+                let bytes = parse_hex(key)?;
+                VerifyingKey::from_bytes(&bytes)
+                    .with_context(|| format!("Invalid trusted key {}", key))
+            })
+            .collect()
+    }
+
+    /// Fails with [`CacheError::UntrustedCommand`] unless the command was signed by a trusted key,
+    /// without trusted keys every command is accepted.
+    pub fn verify(&self, command: &CachedCommand) -> anyhow::Result<()> {
+        let trusted_keys = self.trusted_keys()?;
+        if trusted_keys.is_empty() {
+            return Ok(());
+        }
+        let untrusted = |reason: &str| CacheError::UntrustedCommand {
+            hash: command.hash.clone(),
+            reason: reason.into(),
+        };
+        let Some(signature) = &command.signature else {
+            bail!(untrusted("it is not signed"));
+        };
+        let key = parse_hex(&signature.key)
+            .ok()
+            .and_then(|bytes| VerifyingKey::from_bytes(&bytes).ok())
+            .filter(|key| trusted_keys.contains(key))
+            .ok_or_else(|| untrusted("it is not signed by a trusted key"))?;
+        let signature = parse_hex(&signature.signature)
+            .map(|bytes| Signature::from_bytes(&bytes))
+            .map_err(|_| untrusted("its signature is malformed"))?;
+        key.verify(signed_digest(command).as_bytes(), &signature)
+            .map_err(|_| untrusted("its signature does not match"))?;
+        Ok(())
+    }
+}
+
+pub fn sign(key: &SigningKey, command: &CachedCommand) -> CommandSignature {
+    CommandSignature {
+        key: hex::encode(key.verifying_key().as_bytes()).to_smolstr(),
+        signature: hex::encode(key.sign(signed_digest(command).as_bytes()).to_bytes()).to_smolstr(),
+    }
+}
+
+/// Writes a new hex encoded secret key only readable by the owner, returns its public key.
+pub fn generate_key_file(path: &Path) -> anyhow::Result<SmolStr> {
+    let mut secret = [0u8; SECRET_KEY_LENGTH];
+    getrandom::fill(&mut secret).map_err(|e| anyhow!("Could not generate key: {}", e))?;
+    let key = SigningKey::from_bytes(&secret);
+
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    options
+        .open(path)
+        .and_then(|mut file| file.write_all(hex::encode(key.to_bytes()).as_bytes()))
+        .with_context(|| format!("Could not write signing key to {:?}", path))?;
+    Ok(hex::encode(key.verifying_key().as_bytes()).to_smolstr())
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Utc;
+
+    use super::*;
+    use crate::cache::command::OutputFile;
+
+    fn command() -> CachedCommand {
+        CachedCommand {
+            version: 0,
+            command_line: "make".into(),
+            env: Default::default(),
+            hash: blake3::hash(b"command").to_smolstr(),
+            created: Utc::now(),
+            last_accessed: Utc::now(),
+            log: blake3::hash(b"log").to_smolstr(),
+            output_files: vec![OutputFile {
+                paths: vec!["out/app".into()],
+                hash: blake3::hash(b"app").to_smolstr(),
+            }],
+            project: None,
+            task: None,
+            signature: None,
+        }
+    }
+
+    fn trusting_own_key(dir: &Path) -> (SigningConfig, SigningKey) {
+        let public = generate_key_file(&dir.join("signing.key")).unwrap();
+        let config = SigningConfig {
+            trusted_keys: vec![public],
+            key_file: Some("signing.key".into()),
+        };
+        let key = config.signing_key(dir).unwrap().unwrap();
+        (config, key)
+    }
+
+    #[test]
+    fn signed_commands_are_trusted() {
+        let dir = tempfile::tempdir().unwrap();
+        let (config, key) = trusting_own_key(dir.path());
+        let mut command = command();
+        command.signature = Some(sign(&key, &command));
+        config.verify(&command).unwrap();
+        assert!(generate_key_file(&dir.path().join("signing.key")).is_err());
+    }
+
+    #[test]
+    fn tampered_unsigned_and_foreign_commands_are_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        let (config, key) = trusting_own_key(dir.path());
+        let mut tampered = command();
+        tampered.signature = Some(sign(&key, &tampered));
+        tampered.output_files[0].paths[0] = "/etc/passwd".into();
+        assert!(config.verify(&tampered).is_err());
+
+        assert!(config.verify(&command()).is_err());
+
+        let other = tempfile::tempdir().unwrap();
+        let (_, other_key) = trusting_own_key(other.path());
+        let mut foreign = command();
+        foreign.signature = Some(sign(&other_key, &foreign));
+        assert!(config.verify(&foreign).is_err());
+        SigningConfig::default().verify(&foreign).unwrap();
+    }
+}
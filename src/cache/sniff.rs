@@ -0,0 +1,127 @@
+use std::{fs::File, io::Read, path::Path};
+
+const SAMPLE_SIZE: usize = 64 * 1024;
+/// Shannon entropy in bits per byte above which a sample is considered incompressible.
+const MAX_COMPRESSIBLE_ENTROPY: f64 = 7.5;
+
+const MAGIC_NUMBERS: [(&str, usize, &[u8]); 16] = [
+    ("gzip", 0, &[0x1f, 0x8b]),
+    ("zstd", 0, &[0x28, 0xb5, 0x2f, 0xfd]),
+    ("xz", 0, &[0xfd, b'7', b'z', b'X', b'Z', 0x00]),
+    ("bzip2", 0, b"BZh"),
+    ("lz4", 0, &[0x04, 0x22, 0x4d, 0x18]),
+    ("7z", 0, &[b'7', b'z', 0xbc, 0xaf, 0x27, 0x1c]),
+    ("rar", 0, b"Rar!\x1a\x07"),
+    ("zip/jar", 0, &[b'P', b'K', 0x03, 0x04]),
+    ("png", 0, &[0x89, b'P', b'N', b'G']),
+    ("jpeg", 0, &[0xff, 0xd8, 0xff]),
+    ("gif", 0, b"GIF8"),
+    ("webp", 8, b"WEBP"),
+    ("mp4/mov", 4, b"ftyp"),
+    ("woff", 0, b"wOFF"),
+    ("woff2", 0, b"wOF2"),
+    ("ogg", 0, b"OggS"),
+];
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Content {
+    /// Starts with the magic number of an already compressed format.
+    Compressed(&'static str),
+    /// Bytes are too random to be worth compressing.
+    HighEntropy(f64),
+    Text,
+    Binary,
+}
+
+pub fn sniff_file(path: &Path) -> std::io::Result<Content> {
+    let mut sample = Vec::with_capacity(SAMPLE_SIZE);
+    File::open(path)?
+        .take(SAMPLE_SIZE as u64)
+        .read_to_end(&mut sample)?;
+    Ok(sniff(&sample))
+}
+
+pub fn sniff(sample: &[u8]) -> Content {
+    let magic = MAGIC_NUMBERS.iter().find(|(_, offset, magic)| {
+        sample
+            .get(*offset..offset + magic.len())
+            .is_some_and(|bytes| bytes == *magic)
+    });
+    if let Some((name, _, _)) = magic {
+        return Content::Compressed(name);
+    }
+    let entropy = entropy(sample);
+    if entropy > MAX_COMPRESSIBLE_ENTROPY {
+        Content::HighEntropy(entropy)
+    } else if is_text(sample) {
+        Content::Text
+    } else {
+        Content::Binary
+    }
+}
+
+fn entropy(sample: &[u8]) -> f64 {
+    if sample.is_empty() {
+        return 0.0;
+    }
+    let mut counts = [0u64; 256];
+    for byte in sample {
+        counts[*byte as usize] += 1;
+    }
+    let len = sample.len() as f64;
+    counts
+        .iter()
+        .filter(|count| **count > 0)
+        .map(|count| {
+            let p = *count as f64 / len;
+            -p * p.log2()
+        })
+        .sum()
+}
+
+fn is_text(sample: &[u8]) -> bool {
+    if sample.contains(&0) {
+        return false;
+    }
+    match std::str::from_utf8(sample) {
+        Ok(_) => true,
+        // the sample may cut a multi byte character in half
+        Err(e) => e.error_len().is_none(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn detects_compressed_formats_by_magic_number() {
+        assert_eq!(
+            sniff(&[0x1f, 0x8b, 0x08, 0x00]),
+            Content::Compressed("gzip")
+        );
+        assert_eq!(sniff(b"RIFF\0\0\0\0WEBPVP8 "), Content::Compressed("webp"));
+        // too short for the magic number at its offset
+        assert_ne!(sniff(b"RIFF"), Content::Compressed("webp"));
+    }
+
+    #[test]
+    fn classifies_text_binary_and_random_bytes() {
+        assert_eq!(sniff("hello wörld\n".repeat(100).as_bytes()), Content::Text);
+        assert_eq!(sniff(&[]), Content::Text);
+        let binary = (0..4096u32).map(|i| (i % 16) as u8).collect::<Vec<_>>();
+        assert_eq!(sniff(&binary), Content::Binary);
+        let random = (0..65536u32)
+            .map(|i| (i.wrapping_mul(2_654_435_761) >> 13) as u8)
+            .collect::<Vec<_>>();
+        assert!(matches!(sniff(&random), Content::HighEntropy(_)));
+    }
+
+    #[test]
+    fn text_cut_in_a_multi_byte_character_is_still_text() {
+        let text = "ä".repeat(10);
+        let cut = &text.as_bytes()[..text.len() - 1];
+        assert_eq!(sniff(cut), Content::Text);
+        assert_eq!(sniff(&[b'a', 0xff, b'b']), Content::Binary);
+    }
+}
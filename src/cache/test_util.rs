@@ -0,0 +1,41 @@
+//! Helpers shared by the tests of the cache modules.
+
+use std::{fs, path::Path, sync::Arc};
+
+use blake3::Hash;
+use chrono::Utc;
+use smol_str::ToSmolStr;
+
+use crate::cache::{
+    command::CachedCommand, compression::CompressionPolicy, file::CachedFile,
+    format::FORMAT_VERSION,
+};
+
+/// Stores `content` uncompressed as a loose blob and returns its hash.
+pub async fn store_blob(cache_root: &Path, content: &[u8]) -> Hash {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("blob");
+    fs::write(&path, content).unwrap();
+    let (hash, size) = CachedFile::hash_path(&path).unwrap();
+    let policy = Arc::new(CompressionPolicy::uncompressed());
+    CachedFile::create(cache_root.to_owned(), path, hash, size, policy)
+        .await
+        .unwrap()
+}
+
+/// Record of `echo hello`, its hash derived from `name`.
+pub fn command(name: &str, log: Hash) -> CachedCommand {
+    CachedCommand {
+        version: FORMAT_VERSION,
+        command_line: "echo hello".into(),
+        env: Default::default(),
+        hash: blake3::hash(name.as_bytes()).to_smolstr(),
+        created: Utc::now(),
+        last_accessed: Utc::now(),
+        log: log.to_smolstr(),
+        output_files: vec![],
+        project: None,
+        task: None,
+        signature: None,
+    }
+}
@@ -0,0 +1,273 @@
+use std::{
+    collections::HashSet,
+    fmt::Display,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use blake3::Hash;
+use smol_str::{SmolStr, ToSmolStr};
+use tracing::{debug, info};
+
+use crate::cache::{
+    command::{COMMAND_DIR, COMMAND_FILE_NAME, CachedCommand},
+    file::{COMPRESSED_FILE_NAME, CachedFile, DATA_FILE_NAME, StoredCacheFile},
+    folder::FILE_FOLDER_NAME,
+    format::{is_unknown_version, read_record},
+    pack::{PackIndex, remove_from_pack},
+};
+
+/// Location of a broken entry, used to delete it when repairing.
+#[derive(Debug, Clone)]
+pub enum Entry {
+    LooseBlob(PathBuf),
+    PackedBlob(Hash),
+    Command(PathBuf),
+}
+
+#[derive(Debug, Clone)]
+pub struct Problem {
+    pub entry: Entry,
+    pub reason: String,
+}
+
+impl Display for Problem {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.entry {
+            Entry::LooseBlob(path) | Entry::Command(path) => {
+                write!(f, "{:?}: {}", path, self.reason)
+            }
+            Entry::PackedBlob(hash) => write!(f, "packed blob {}: {}", hash, self.reason),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct VerifyReport {
+    pub commands: usize,
+    pub blobs: usize,
+    pub problems: Vec<Problem>,
+    pub repaired: usize,
+}
+
+fn entries(dir: &Path) -> anyhow::Result<Vec<PathBuf>> {
+    if !dir.exists() {
+        return Ok(vec![]);
+    }
+    let mut paths = fs::read_dir(dir)?
+        .map(|entry| Ok(entry?.path()))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    paths.sort();
+    Ok(paths)
+}
+
+/// Checks that a loose blob directory was written completely and that its content matches.
+async fn check_loose_blob(cache_root: &Path, dir: &Path) -> Result<(), String> {
+    let name = dir.file_name().map(|n| n.to_string_lossy().to_smolstr());
+    let hash = name
+        .as_ref()
+        .and_then(|name| Hash::from_hex(name.as_bytes()).ok())
+        .ok_or("not named after a blob hash")?;
+    let json_path = dir.join(DATA_FILE_NAME);
+    if !json_path.exists() {
+        return Err(format!("incomplete, \"{}\" is missing", DATA_FILE_NAME));
+    }
+    let data: StoredCacheFile = match read_record(&json_path) {
+        Err(e) if is_unknown_version(&e) => {
+            debug!("Skipping {:#}", e);
+            return Ok(());
+        }
+        data => data.map_err(|e| format!("{:#}", e))?,
+    };
+    if data.chunks.is_none() && !dir.join(COMPRESSED_FILE_NAME).exists() {
+        return Err(format!(
+            "incomplete, \"{}\" is missing",
+            COMPRESSED_FILE_NAME
+        ));
+    }
+    check_content(cache_root, &hash).await
+}
+
+async fn check_content(cache_root: &Path, hash: &Hash) -> Result<(), String> {
+    let file = CachedFile::open(cache_root, hash).map_err(|e| format!("unreadable: {:#}", e))?;
+    file.hash_content()
+        .await
+        .map_err(|e| format!("{:#}", e.root_cause()))?;
+    Ok(())
+}
+
+fn check_command(dir: &Path, broken: &HashSet<SmolStr>, cache_root: &Path) -> Result<(), String> {
+    let json_path = dir.join(COMMAND_FILE_NAME);
+    if !json_path.exists() {
+        return Err(format!("incomplete, \"{}\" is missing", COMMAND_FILE_NAME));
+    }
+    let hash = dir.file_name().unwrap_or_default().to_string_lossy();
+    let command = match CachedCommand::load(&json_path, &hash) {
+        Err(e) if is_unknown_version(&e) => {
+            debug!("Skipping {:#}", e);
+            return Ok(());
+        }
+        command => command.map_err(|e| format!("{:#}", e))?,
+    };
+    for blob in command.referenced_blobs() {
+        if broken.contains(&blob) {
+            return Err(format!("references broken blob {}", blob));
+        }
+        let hash = Hash::from_hex(blob.as_bytes())
+            .map_err(|_| format!("references invalid blob hash {}", blob))?;
+        if !CachedFile::is_stored(cache_root, &hash).unwrap_or(false) {
+            return Err(format!("references missing blob {}", blob));
+        }
+    }
+    Ok(())
+}
+
+fn remove(cache_root: &Path, entry: &Entry) -> anyhow::Result<()> {
+    match entry {
+        Entry::LooseBlob(path) | Entry::Command(path) => fs::remove_dir_all(path)?,
+        Entry::PackedBlob(hash) => {
+            remove_from_pack(cache_root, hash)?;
+        }
+    }
+    Ok(())
+}
+
+/// Re-hashes every blob and checks every command record of the cache folder.
+/// With `repair` broken entries are deleted, commands are checked after the blobs were
+/// removed so commands referencing them are deleted as well.
+pub async fn verify(cache_root: &Path, repair: bool) -> anyhow::Result<VerifyReport> {
+    let mut report = VerifyReport::default();
+    let mut broken = HashSet::new();
+
+    for dir in entries(&cache_root.join(FILE_FOLDER_NAME))? {
+        report.blobs += 1;
+        match check_loose_blob(cache_root, &dir).await {
+            Ok(()) => debug!("Verified {:?}", dir),
+            Err(reason) => {
+                if let Some(name) = dir.file_name() {
+                    broken.insert(name.to_string_lossy().to_smolstr());
+                }
+                report.problems.push(Problem {
+                    entry: Entry::LooseBlob(dir),
+                    reason,
+                });
+            }
+        }
+    }
+
+    let pack_index = PackIndex::load(cache_root)?;
+    let mut packed = pack_index
+        .iter()
+        .filter(|(hash, _)| {
+            !cache_root
+                .join(FILE_FOLDER_NAME)
+                .join(hash.as_str())
+                .exists()
+        })
+        .map(|(hash, _)| Hash::from_hex(hash.as_bytes()))
+        .collect::<Result<Vec<_>, _>>()?;
+    packed.sort_by_key(|hash| hash.to_string());
+    for hash in packed {
+        report.blobs += 1;
+        match check_content(cache_root, &hash).await {
+            Ok(()) => debug!("Verified packed blob {}", hash),
+            Err(reason) => {
+                broken.insert(hash.to_smolstr());
+                report.problems.push(Problem {
+                    entry: Entry::PackedBlob(hash),
+                    reason,
+                });
+            }
+        }
+    }
+
+    if repair {
+        for problem in &report.problems {
+            remove(cache_root, &problem.entry)?;
+            report.repaired += 1;
+        }
+        PackIndex::invalidate(cache_root);
+    }
+
+    let mut command_problems = Vec::new();
+    for dir in entries(&cache_root.join(COMMAND_DIR))? {
+        report.commands += 1;
+        if let Err(reason) = check_command(&dir, &broken, cache_root) {
+            command_problems.push(Problem {
+                entry: Entry::Command(dir),
+                reason,
+            });
+        }
+    }
+    if repair {
+        for problem in &command_problems {
+            remove(cache_root, &problem.entry)?;
+            report.repaired += 1;
+        }
+    }
+    report.problems.extend(command_problems);
+
+    info!(
+        "Verified {} commands and {} files",
+        report.commands, report.blobs
+    );
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cache::{
+        command::OutputFile,
+        test_util::{command, store_blob},
+    };
+
+    #[tokio::test]
+    async fn repair_removes_broken_blobs_and_their_commands() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache_root = dir.path();
+        let log = store_blob(cache_root, b"hello\n").await;
+        let output = store_blob(cache_root, b"output\n").await;
+        let blob = cache_root
+            .join(FILE_FOLDER_NAME)
+            .join(output.to_string())
+            .join(COMPRESSED_FILE_NAME);
+        let mut stored = fs::read(&blob).unwrap();
+        stored[0] ^= 1;
+        fs::write(&blob, stored).unwrap();
+
+        let good = command("good", log);
+        let broken = CachedCommand {
+            output_files: vec![OutputFile {
+                paths: vec!["out.txt".into()],
+                hash: output.to_smolstr(),
+            }],
+            ..command("broken", log)
+        };
+        let command_dir = cache_root.join(COMMAND_DIR).join(broken.hash.as_str());
+        good.clone().store_in_cache(cache_root).await.unwrap();
+        broken.store_in_cache(cache_root).await.unwrap();
+
+        let report = verify(cache_root, false).await.unwrap();
+        assert_eq!((report.commands, report.blobs), (2, 2));
+        assert_eq!(report.problems.len(), 2, "{:?}", report.problems);
+        assert!(report.problems[1].reason.contains("references broken blob"));
+        assert_eq!(report.repaired, 0);
+        assert!(blob.exists() && command_dir.exists());
+
+        let report = verify(cache_root, true).await.unwrap();
+        assert_eq!(report.repaired, 2);
+        assert!(!blob.exists() && !command_dir.exists());
+
+        let report = verify(cache_root, false).await.unwrap();
+        assert_eq!((report.commands, report.blobs), (1, 1));
+        assert!(report.problems.is_empty(), "{:?}", report.problems);
+        assert!(CachedFile::is_stored(cache_root, &log).unwrap());
+        assert!(
+            cache_root
+                .join(COMMAND_DIR)
+                .join(good.hash.as_str())
+                .exists()
+        );
+    }
+}
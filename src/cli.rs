@@ -1,15 +1,20 @@
-use clap::{Parser, arg, command};
+use std::path::PathBuf;
+
+use clap::{Parser, Subcommand};
 
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
 pub struct CommandLineArgs {
+    #[command(subcommand)]
+    pub action: Option<Action>,
+
     #[arg(last = true)]
     pub command: Vec<String>,
 
-    #[arg(short)]
+    #[arg(short, global = true)]
     pub config: Option<String>,
 
-    #[arg(long)]
+    #[arg(long, global = true)]
     #[arg(default_value = "false")]
     pub verbose: bool,
 
@@ -17,6 +22,130 @@ pub struct CommandLineArgs {
     #[arg(default_value = "false")]
     pub clear: bool,
 
-    #[arg(short)]
+    #[arg(short, long, global = true)]
     pub project: Option<String>,
 }
+
+#[derive(Subcommand, Debug)]
+pub enum Action {
+    /// Train a zstd dictionary from the current output files of a project, used for small outputs
+    TrainDictionary {
+        /// Maximum size of the dictionary in bytes
+        #[arg(long, default_value = "112640")]
+        size: usize,
+        /// Maximum amount of output files to sample
+        #[arg(long, default_value = "2000")]
+        samples: usize,
+    },
+    /// Move small loose blobs of the cache folder into pack files, e.g. before uploading the cache
+    Repack {
+        /// Blobs up to this size in bytes are packed
+        #[arg(long, default_value = "1048576")]
+        max_blob_size: u64,
+        /// Start a new pack file once a pack reaches this size in bytes
+        #[arg(long, default_value = "268435456")]
+        max_pack_size: u64,
+    },
+    /// Write the command records of a project (or all) with their files into a single tar archive
+    Export {
+        /// Only export commands accessed within this duration, e.g. 3d, 12h or 30m
+        #[arg(long, value_parser = parse_duration)]
+        since: Option<chrono::Duration>,
+        /// Also export the command with this hash, can be repeated
+        #[arg(long = "command")]
+        commands: Vec<String>,
+        /// Archive to write, compressed with zstd if the name ends with .zst
+        #[arg(short, long)]
+        output: PathBuf,
+    },
+    /// Merge an archive written by export into the cache, keeping newer cached commands
+    Import { archive: PathBuf },
+    /// Write a new ed25519 key for signing command records and print its public key
+    GenerateSigningKey {
+        /// File to write the hex encoded secret key to, it must not exist yet
+        output: PathBuf,
+    },
+    /// Run a task defined in the config, e.g. main:build, or build of the selected project
+    Run {
+        target: String,
+        /// Run the task in every project that defines it, in dependency order
+        #[arg(long, conflicts_with = "with_deps")]
+        all: bool,
+        /// Run the task in the dependencies of the project first
+        #[arg(long)]
+        with_deps: bool,
+        /// Maximum amount of projects running in parallel
+        #[arg(short, long, default_value_t = num_cpus::get())]
+        jobs: usize,
+    },
+    /// Print the projects affected by the changes since a git ref, or run a task in them
+    Affected {
+        /// Git ref to compare against, e.g. origin/main
+        #[arg(long)]
+        base: String,
+        /// Run this task in the affected projects that define it, in dependency order
+        #[arg(long)]
+        run: Option<String>,
+        /// Maximum amount of projects running in parallel
+        #[arg(short, long, default_value_t = num_cpus::get())]
+        jobs: usize,
+    },
+    /// Print the JSON schema of the config, e.g. for editor validation and completion
+    Schema {
+        /// Print the schema of a project file instead
+        #[arg(long)]
+        project_file: bool,
+    },
+    /// Upgrade the cache folder in place to the format version of this build
+    Migrate,
+    /// Check every command record and re-hash every file of the cache, fails if anything is broken
+    #[command(alias = "fsck")]
+    Verify {
+        /// Delete broken entries instead of failing
+        #[arg(long, default_value = "false")]
+        repair: bool,
+    },
+}
+
+/// Parses durations like `30s`, `15m`, `12h`, `3d` or `2w`.
+pub fn parse_duration(value: &str) -> Result<chrono::Duration, String> {
+    let invalid = || format!("invalid duration \"{}\", expected e.g. 3d", value);
+    let Some((split, _)) = value.char_indices().last() else {
+        return Err(invalid());
+    };
+    let (amount, unit) = value.split_at(split);
+    let amount = amount.parse::<u32>().map_err(|_| invalid())?.into();
+    let duration = match unit {
+        "s" => chrono::Duration::try_seconds(amount),
+        "m" => chrono::Duration::try_minutes(amount),
+        "h" => chrono::Duration::try_hours(amount),
+        "d" => chrono::Duration::try_days(amount),
+        "w" => chrono::Duration::try_weeks(amount),
+        _ => {
+            return Err(format!(
+                "invalid duration unit in \"{}\", expected one of s, m, h, d, w",
+                value
+            ));
+        }
+    };
+    duration.ok_or_else(invalid)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_durations() {
+        assert_eq!(parse_duration("30s"), Ok(chrono::Duration::seconds(30)));
+        assert_eq!(parse_duration("3d"), Ok(chrono::Duration::days(3)));
+        assert_eq!(parse_duration("2w"), Ok(chrono::Duration::weeks(2)));
+    }
+
+    #[test]
+    fn rejects_invalid_durations() {
+        for value in ["", "d", "5", "5µ", "µ", "-5d", "3x", "99999999999w"] {
+            assert!(parse_duration(value).is_err(), "{}", value);
+        }
+    }
+}
@@ -0,0 +1,254 @@
+use std::{
+    collections::BTreeSet,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use anyhow::{Context, bail};
+use glob::{MatchOptions, Pattern};
+use smol_str::SmolStr;
+use tracing::{debug, trace};
+
+use crate::config::{Config, project::Project};
+
+fn git(root: &Path, args: &[&str]) -> anyhow::Result<String> {
+    let output = Command::new("git")
+        .current_dir(root)
+        .args(args)
+        .output()
+        .context("Could not run git")?;
+    if !output.status.success() {
+        bail!(
+            "git {} failed: {}",
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(String::from_utf8(output.stdout)?)
+}
+
+/// Files changed since the merge base with `base`, including uncommitted and untracked ones,
+/// relative to `root`. Changes outside of `root` or inside the cache folder are dropped.
+pub fn changed_files(
+    root: &Path,
+    cache_folder: &Path,
+    base: &str,
+) -> anyhow::Result<BTreeSet<PathBuf>> {
+    let toplevel = PathBuf::from(git(root, &["rev-parse", "--show-toplevel"])?.trim());
+    let root = root.canonicalize()?;
+    let cache_folder = cache_folder
+        .canonicalize()
+        .unwrap_or_else(|_| cache_folder.to_owned());
+    let range = format!("{}...HEAD", base);
+    let listings = [
+        git(&root, &["diff", "--name-only", "-z", &range])?,
+        git(&root, &["diff", "--name-only", "-z", "HEAD"])?,
+        git(
+            &root,
+            &[
+                "ls-files",
+                "-z",
+                "--full-name",
+                "--others",
+                "--exclude-standard",
+            ],
+        )?,
+    ];
+    let changed = listings
+        .iter()
+        .flat_map(|listing| listing.split_terminator('\0'))
+        .filter_map(|line| {
+            let path = toplevel.join(line);
+            if path.starts_with(&cache_folder) {
+                return None;
+            }
+            let relative = path.strip_prefix(&root).ok()?.to_owned();
+            trace!("Changed file {:?}", relative);
+            Some(relative)
+        })
+        .collect();
+    Ok(changed)
+}
+
+fn matches_inputs(project: &Project, changed: &BTreeSet<PathBuf>) -> anyhow::Result<bool> {
+    // like the glob walk, `*` doesn't match across directories
+    let options = MatchOptions {
+        require_literal_separator: true,
+        ..Default::default()
+    };
+    let inputs = project
+        .inputs
+        .iter()
+        .chain(project.tasks.values().flat_map(|task| &task.inputs));
+    for input in inputs {
+        // changed paths never start with "./", inputs relative to a root of "." can
+        let pattern = Pattern::new(input.trim_start_matches("./"))?;
+        if let Some(path) = changed
+            .iter()
+            .find(|path| pattern.matches_path_with(path, options))
+        {
+            debug!(
+                "Project {} is affected by {:?} matching {}",
+                project.name, path, input
+            );
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+/// Projects with an input or their project file among the changed files and every project
+/// depending on them. A changed config file affects all projects.
+pub fn affected_projects(
+    config: &Config,
+    changed: &BTreeSet<PathBuf>,
+) -> anyhow::Result<BTreeSet<SmolStr>> {
+    let projects = &config.projects;
+    if let Some(source) = &config.source
+        && changed.contains(Path::new(source.as_str()))
+    {
+        debug!("Config file {} changed, all projects are affected", source);
+        return Ok(projects.iter().map(|p| p.name.clone()).collect());
+    }
+    let mut affected = BTreeSet::new();
+    for project in projects {
+        if let Some(source) = &project.source
+            && changed.contains(Path::new(source.as_str()))
+        {
+            debug!("Project {} is affected by its project file", project.name);
+            affected.insert(project.name.clone());
+        } else if matches_inputs(project, changed)? {
+            affected.insert(project.name.clone());
+        }
+    }
+    let mut stack = affected.iter().cloned().collect::<Vec<_>>();
+    while let Some(name) = stack.pop() {
+        for dependent in projects.iter().filter(|p| p.depends_on.contains(&name)) {
+            if affected.insert(dependent.name.clone()) {
+                debug!("Project {} is affected through {}", dependent.name, name);
+                stack.push(dependent.name.clone());
+            }
+        }
+    }
+    Ok(affected)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::config::parse::parse_config_file;
+
+    fn commit_all(root: &Path, message: &str) {
+        git(root, &["add", "-A"]).unwrap();
+        git(
+            root,
+            &[
+                "-c",
+                "user.name=test",
+                "-c",
+                "user.email=test@example.com",
+                "commit",
+                "-qm",
+                message,
+            ],
+        )
+        .unwrap();
+    }
+
+    fn write(root: &Path, path: &str, content: &str) {
+        let path = root.join(path);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, content).unwrap();
+    }
+
+    /// A repository with `lib`, `app` depending on it and an included `svc/api`.
+    fn repository() -> tempfile::TempDir {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        git(root, &["init", "-q", "-b", "main"]).unwrap();
+        write(
+            root,
+            "cache.yml",
+            "cache_dir: .cache\nttl: 7\ninclude: [svc/*]\nprojects:\n  - name: lib\n    root: ./lib\n    inputs: [\"lib/**/*\"]\n  - name: app\n    root: ./app\n    inputs: [\"app/*\"]\n    depends_on: [lib]\n",
+        );
+        write(
+            root,
+            "svc/api/cache.project.yml",
+            "name: api\ninputs: [\"src/*\"]\n",
+        );
+        write(root, "svc/api/src/main.txt", "api");
+        write(root, "lib/src/lib.txt", "lib");
+        write(root, "app/app.txt", "app");
+        commit_all(root, "initial");
+        git(root, &["checkout", "-q", "-b", "feature"]).unwrap();
+        dir
+    }
+
+    fn affected(root: &Path) -> BTreeSet<SmolStr> {
+        let config = parse_config_file(&root.join("cache.yml"), None).unwrap();
+        let changed = changed_files(root, &root.join(".cache"), "main").unwrap();
+        affected_projects(&config, &changed).unwrap()
+    }
+
+    #[test]
+    fn committed_uncommitted_and_untracked_changes() {
+        let dir = repository();
+        let root = dir.path();
+        write(root, "lib/src/lib.txt", "changed");
+        commit_all(root, "change lib");
+        write(root, "app/app.txt", "uncommitted");
+        write(root, "app/new.txt", "untracked");
+
+        let changed = changed_files(root, &root.join(".cache"), "main").unwrap();
+        let expected = ["app/app.txt", "app/new.txt", "lib/src/lib.txt"];
+        assert_eq!(changed, expected.iter().map(PathBuf::from).collect());
+    }
+
+    #[test]
+    fn dependents_are_affected() {
+        let dir = repository();
+        write(dir.path(), "lib/src/lib.txt", "changed");
+        assert_eq!(
+            affected(dir.path()),
+            BTreeSet::from(["app".into(), "lib".into()])
+        );
+    }
+
+    #[test]
+    fn unrelated_changes_affect_nothing() {
+        let dir = repository();
+        write(dir.path(), "docs/readme.md", "docs");
+        assert!(affected(dir.path()).is_empty());
+    }
+
+    #[test]
+    fn cache_folder_is_not_a_change() {
+        let dir = repository();
+        write(dir.path(), ".cache/commands/abc/command.json", "{}");
+        let changed = changed_files(dir.path(), &dir.path().join(".cache"), "main").unwrap();
+        assert!(changed.is_empty());
+    }
+
+    #[test]
+    fn config_file_change_affects_all_projects() {
+        let dir = repository();
+        let root = dir.path();
+        let config = fs::read_to_string(root.join("cache.yml")).unwrap();
+        write(root, "cache.yml", &format!("{}# comment\n", config));
+        let all = BTreeSet::from(["api".into(), "app".into(), "lib".into()]);
+        assert_eq!(affected(root), all);
+    }
+
+    #[test]
+    fn project_file_change_affects_its_project() {
+        let dir = repository();
+        write(
+            dir.path(),
+            "svc/api/cache.project.yml",
+            "name: api\ninputs: [\"src/*\"]\ntasks:\n  build: make\n",
+        );
+        assert_eq!(affected(dir.path()), BTreeSet::from(["api".into()]));
+    }
+}
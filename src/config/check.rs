@@ -0,0 +1,206 @@
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+};
+
+use smol_str::SmolStr;
+
+use crate::config::project::Project;
+
+fn duplicate_names(projects: &[Project]) -> Vec<String> {
+    let mut origins: BTreeMap<&str, Vec<String>> = BTreeMap::new();
+    for project in projects {
+        let origin = project
+            .location()
+            .unwrap_or_else(|| "the config file".to_owned());
+        origins
+            .entry(project.name.as_str())
+            .or_default()
+            .push(origin);
+    }
+    origins
+        .into_iter()
+        .filter(|(_, origins)| origins.len() > 1)
+        .map(|(name, origins)| {
+            format!(
+                "project name {} is used by {} projects, defined in {}",
+                name,
+                origins.len(),
+                origins.join(", ")
+            )
+        })
+        .collect()
+}
+
+fn invalid_globs(project: &Project, field: &str, patterns: &[SmolStr]) -> Vec<String> {
+    patterns
+        .iter()
+        .filter_map(|pattern| {
+            glob::Pattern::new(pattern).err().map(|e| {
+                format!(
+                    "project {} has invalid {} pattern \"{}\": {}",
+                    project.label(),
+                    field,
+                    pattern,
+                    e
+                )
+            })
+        })
+        .collect()
+}
+
+/// Resolves every root, roots that don't exist are reported instead.
+fn resolve_roots<'a>(
+    projects: &'a [Project],
+    root: &Path,
+    errors: &mut Vec<String>,
+) -> Vec<(&'a Project, PathBuf)> {
+    let mut roots = Vec::new();
+    for project in projects {
+        let path = root.join(project.root.as_str());
+        match path.canonicalize() {
+            Ok(path) if path.is_dir() => roots.push((project, path)),
+            Ok(_) => errors.push(format!(
+                "project {} has root {:?} which is not a directory",
+                project.label(),
+                path
+            )),
+            Err(_) => errors.push(format!(
+                "project {} has root {:?} which does not exist",
+                project.label(),
+                path
+            )),
+        }
+    }
+    roots
+}
+
+/// Projects sharing a root can't be told apart when selecting the project for the cwd.
+/// Nested roots are fine, the deepest one is selected.
+fn shared_roots(roots: &[(&Project, PathBuf)]) -> Vec<String> {
+    let mut errors = Vec::new();
+    for (index, (project, path)) in roots.iter().enumerate() {
+        for (other, other_path) in &roots[index + 1..] {
+            if path == other_path && project.name != other.name {
+                errors.push(format!(
+                    "projects {} and {} share the root {:?}",
+                    project.label(),
+                    other.label(),
+                    path
+                ));
+            }
+        }
+    }
+    errors
+}
+
+/// Working directories of the projects and their tasks have to be folders inside the root,
+/// `workdir: ../..` would run the command somewhere else.
+fn invalid_workdirs(roots: &[(&Project, PathBuf)]) -> Vec<String> {
+    let mut errors = Vec::new();
+    for (project, path) in roots {
+        let workdirs = project.workdir.iter().map(|w| ("", w)).chain(
+            project
+                .tasks
+                .iter()
+                .filter_map(|(name, task)| Some((name.as_str(), task.workdir.as_ref()?))),
+        );
+        for (task, workdir) in workdirs {
+            let owner = match task {
+                "" => format!("project {}", project.label()),
+                task => format!("task {} of project {}", task, project.label()),
+            };
+            let workdir = path.join(workdir.as_str());
+            match workdir.canonicalize() {
+                Ok(resolved) if !resolved.is_dir() => errors.push(format!(
+                    "{} has workdir {:?} which is not a directory",
+                    owner, workdir
+                )),
+                Ok(resolved) if !resolved.starts_with(path) => errors.push(format!(
+                    "{} has workdir {:?} which is outside of its root {:?}",
+                    owner, workdir, path
+                )),
+                Ok(_) => {}
+                Err(_) => errors.push(format!(
+                    "{} has workdir {:?} which does not exist",
+                    owner, workdir
+                )),
+            }
+        }
+    }
+    errors
+}
+
+/// Checks names, roots, working directories and glob patterns of the projects and their tasks,
+/// `root` is the folder of the config file.
+pub fn validate_projects(projects: &[Project], root: &Path) -> Vec<String> {
+    let mut errors = duplicate_names(projects);
+    let roots = resolve_roots(projects, root, &mut errors);
+    errors.extend(shared_roots(&roots));
+    errors.extend(invalid_workdirs(&roots));
+    for project in projects {
+        errors.extend(invalid_globs(project, "input", &project.inputs));
+        errors.extend(invalid_globs(project, "output", &project.outputs));
+        if project.name.contains(':') {
+            errors.push(format!(
+                "project name {} must not contain ':', it separates project and task",
+                project.name
+            ));
+        }
+        for (name, task) in &project.tasks {
+            if name.contains(':') {
+                errors.push(format!(
+                    "project {} has task {} which must not contain ':'",
+                    project.label(),
+                    name
+                ));
+            }
+            errors.extend(invalid_globs(project, "task input", &task.inputs));
+            errors.extend(invalid_globs(project, "task output", &task.outputs));
+        }
+    }
+    errors
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::config::test_util::projects;
+
+    #[test]
+    fn workdir_must_stay_inside_the_root() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir_all(dir.path().join("repo/app/src")).unwrap();
+        let root = dir.path().join("repo");
+
+        let inside = projects("- {name: app, root: app, workdir: src}");
+        assert!(validate_projects(&inside, &root).is_empty());
+
+        let outside =
+            projects("- {name: app, root: app, tasks: {up: {command: ls, workdir: ../..}}}");
+        let errors = validate_projects(&outside, &root);
+        assert_eq!(errors.len(), 1, "{:?}", errors);
+        assert!(errors[0].contains("outside of its root"), "{:?}", errors);
+
+        let missing = projects("- {name: app, root: app, workdir: nope}");
+        let errors = validate_projects(&missing, &root);
+        assert!(errors[0].contains("does not exist"), "{:?}", errors);
+    }
+
+    #[test]
+    fn roots_may_be_nested_but_not_shared() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir_all(dir.path().join("services/api")).unwrap();
+
+        let nested = projects("- {name: all, root: .}\n- {name: api, root: services/api}");
+        assert!(validate_projects(&nested, dir.path()).is_empty());
+
+        let shared =
+            projects("- {name: api, root: services/api}\n- {name: other, root: ./services/api/}");
+        let errors = validate_projects(&shared, dir.path());
+        assert_eq!(errors.len(), 1, "{:?}", errors);
+        assert!(errors[0].contains("share the root"), "{:?}", errors);
+    }
+}
@@ -0,0 +1,262 @@
+use std::{borrow::Cow, fmt::Display, path::Path, str::FromStr};
+
+use anyhow::{Context, anyhow, bail};
+use itertools::Itertools;
+use schemars::{JsonSchema, Schema, SchemaGenerator, json_schema};
+use serde::{Deserialize, Serialize};
+use smol_str::SmolStr;
+use tokio::process::Command;
+
+/// How a command is started, either by an interpreter getting the command line as its last
+/// argument or directly from its arguments.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum Executor {
+    Interpreter {
+        program: SmolStr,
+        args: Vec<SmolStr>,
+    },
+    Direct,
+}
+
+impl Executor {
+    const SHELLS: [&str; 3] = ["sh", "bash", "zsh"];
+
+    fn is_shell(program: &str) -> bool {
+        Path::new(program)
+            .file_name()
+            .and_then(|name| name.to_str())
+            .is_some_and(|name| Self::SHELLS.contains(&name))
+    }
+
+    /// `-c` or a cluster of short options ending in it, like `-ec`.
+    fn is_command_flag(arg: &str) -> bool {
+        arg.strip_prefix('-')
+            .is_some_and(|flags| !flags.starts_with('-') && flags.ends_with('c'))
+    }
+
+    fn shell(program: &str) -> Self {
+        Self::Interpreter {
+            program: program.into(),
+            args: vec!["-c".into()],
+        }
+    }
+
+    /// The command line identifying `argv` in the cache key and the log. Interpreters get the
+    /// arguments joined by spaces, direct commands quote them so no boundary is lost.
+    pub fn command_line(&self, argv: &[String]) -> anyhow::Result<String> {
+        match self {
+            Executor::Interpreter { .. } => Ok(argv.join(" ")),
+            Executor::Direct => shlex::try_join(argv.iter().map(String::as_str))
+                .map_err(|e| anyhow!("Could not quote command {:?}: {}", argv, e)),
+        }
+    }
+
+    /// The arguments of a task command, direct commands are split like a shell would.
+    pub fn task_argv(&self, command: &str) -> anyhow::Result<Vec<String>> {
+        match self {
+            Executor::Interpreter { .. } => Ok(vec![command.to_owned()]),
+            Executor::Direct => shlex::split(command)
+                .ok_or_else(|| anyhow!("Could not split command \"{}\" into arguments", command)),
+        }
+    }
+
+    pub fn command(&self, argv: &[String]) -> anyhow::Result<Command> {
+        match self {
+            Executor::Interpreter { program, args } => {
+                let mut command = Command::new(program.as_str());
+                command.args(args.iter().map(SmolStr::as_str));
+                command.arg(argv.join(" "));
+                Ok(command)
+            }
+            Executor::Direct => {
+                let Some((program, args)) = argv.split_first() else {
+                    bail!("Can not run an empty command directly");
+                };
+                let mut command = Command::new(program);
+                command.args(args);
+                Ok(command)
+            }
+        }
+    }
+
+    pub fn program(&self, argv: &[String]) -> String {
+        match self {
+            Executor::Interpreter { program, .. } => program.to_string(),
+            Executor::Direct => argv.first().cloned().unwrap_or_default(),
+        }
+    }
+}
+
+impl Default for Executor {
+    fn default() -> Self {
+        Self::shell("bash")
+    }
+}
+
+impl FromStr for Executor {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "direct" => return Ok(Self::Direct),
+            // the former ExecutionEnvironment values
+            "BASH" => return Ok(Self::shell("bash")),
+            "SHELL" => return Ok(Self::shell("sh")),
+            _ => {}
+        }
+        let words = shlex::split(s).with_context(|| format!("Invalid executor \"{}\"", s))?;
+        let Some((program, args)) = words.split_first() else {
+            bail!("Executor must not be empty");
+        };
+        if !Self::SHELLS.contains(&program.as_str()) && !program.starts_with('/') {
+            bail!(
+                "Unknown executor \"{}\", expected direct, one of {} or an absolute path",
+                program,
+                Self::SHELLS.join(", ")
+            );
+        }
+        if args.is_empty() {
+            return Ok(Self::shell(program));
+        }
+        let mut args = args.iter().map(SmolStr::from).collect::<Vec<_>>();
+        // without -c a shell takes the command line for a script path
+        if Self::is_shell(program) && !args.last().is_some_and(|arg| Self::is_command_flag(arg)) {
+            args.push("-c".into());
+        }
+        Ok(Self::Interpreter {
+            program: program.into(),
+            args,
+        })
+    }
+}
+
+impl TryFrom<String> for Executor {
+    type Error = anyhow::Error;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl Display for Executor {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Executor::Interpreter { program, args } => {
+                let words = std::iter::once(program).chain(args).map(SmolStr::as_str);
+                // only nul bytes can't be quoted
+                let joined =
+                    shlex::try_join(words.clone()).unwrap_or_else(|_| words.clone().join(" "));
+                write!(f, "{}", joined)
+            }
+            Executor::Direct => write!(f, "direct"),
+        }
+    }
+}
+
+impl From<Executor> for String {
+    fn from(value: Executor) -> Self {
+        value.to_string()
+    }
+}
+
+impl JsonSchema for Executor {
+    fn schema_name() -> Cow<'static, str> {
+        "Executor".into()
+    }
+
+    fn json_schema(_: &mut SchemaGenerator) -> Schema {
+        json_schema!({
+            "type": "string",
+            "description": "direct, sh, bash, zsh or an absolute interpreter path, optionally followed by its arguments, e.g. \"bash -euo pipefail -c\"",
+            "examples": ["bash", "direct", "bash -euo pipefail -c", "/usr/bin/python3 -c"],
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(words: &[&str]) -> Vec<String> {
+        words.iter().map(|w| w.to_string()).collect()
+    }
+
+    #[test]
+    fn parses_shells_interpreters_and_legacy_values() {
+        assert_eq!("bash".parse::<Executor>().unwrap(), Executor::shell("bash"));
+        assert_eq!("BASH".parse::<Executor>().unwrap(), Executor::shell("bash"));
+        assert_eq!("SHELL".parse::<Executor>().unwrap(), Executor::shell("sh"));
+        assert_eq!("sh".parse::<Executor>().unwrap(), Executor::shell("sh"));
+        assert_eq!("direct".parse::<Executor>().unwrap(), Executor::Direct);
+        assert_eq!(
+            "bash -euo pipefail -c".parse::<Executor>().unwrap(),
+            interpreter("bash", &["-euo", "pipefail", "-c"])
+        );
+        assert_eq!(
+            "/usr/bin/python3 -c".parse::<Executor>().unwrap(),
+            interpreter("/usr/bin/python3", &["-c"])
+        );
+    }
+
+    fn interpreter(program: &str, args: &[&str]) -> Executor {
+        Executor::Interpreter {
+            program: program.into(),
+            args: args.iter().map(|arg| SmolStr::from(*arg)).collect(),
+        }
+    }
+
+    #[test]
+    fn shells_get_the_missing_command_flag() {
+        assert_eq!(
+            "bash -euo pipefail".parse::<Executor>().unwrap(),
+            interpreter("bash", &["-euo", "pipefail", "-c"])
+        );
+        assert_eq!(
+            "/bin/sh -e".parse::<Executor>().unwrap(),
+            interpreter("/bin/sh", &["-e", "-c"])
+        );
+        assert_eq!(
+            "bash -ec".parse::<Executor>().unwrap(),
+            interpreter("bash", &["-ec"])
+        );
+        // other interpreters are taken as given
+        assert_eq!(
+            "/usr/bin/node -e".parse::<Executor>().unwrap(),
+            interpreter("/usr/bin/node", &["-e"])
+        );
+    }
+
+    #[test]
+    fn rejects_unknown_and_empty_executors() {
+        assert!("python3 -c".parse::<Executor>().is_err());
+        assert!("".parse::<Executor>().is_err());
+        assert!("bash 'unclosed".parse::<Executor>().is_err());
+    }
+
+    #[test]
+    fn display_round_trips() {
+        for value in [
+            "bash -c",
+            "direct",
+            "/usr/bin/python3 -c",
+            "bash -c 'set -e;'",
+        ] {
+            let executor: Executor = value.parse().unwrap();
+            assert_eq!(executor.to_string().parse::<Executor>().unwrap(), executor);
+        }
+    }
+
+    #[test]
+    fn direct_commands_keep_argument_boundaries() {
+        let argv = args(&["printf", "%s\n", "a b"]);
+        let line = Executor::Direct.command_line(&argv).unwrap();
+        assert_ne!(line, Executor::default().command_line(&argv).unwrap());
+        assert_eq!(Executor::Direct.task_argv(&line).unwrap(), argv);
+        assert!(Executor::Direct.task_argv("echo 'unclosed").is_err());
+        assert_eq!(
+            Executor::default().task_argv("echo 'a b'").unwrap(),
+            args(&["echo 'a b'"])
+        );
+    }
+}
@@ -0,0 +1,216 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+use itertools::Itertools;
+use smol_str::SmolStr;
+
+use crate::config::project::Project;
+
+/// Minimal similarity for a project name to be suggested for a misspelled dependency.
+const SUGGESTION_THRESHOLD: f64 = 0.7;
+
+pub(crate) fn suggestion<'a>(
+    name: &str,
+    candidates: impl Iterator<Item = &'a SmolStr>,
+) -> Option<&'a str> {
+    candidates
+        .map(|candidate| (strsim::jaro(name, candidate), candidate))
+        .filter(|(similarity, _)| *similarity >= SUGGESTION_THRESHOLD)
+        .max_by(|a, b| a.0.total_cmp(&b.0))
+        .map(|(_, candidate)| candidate.as_str())
+}
+
+fn unknown_dependencies(projects: &[Project], by_name: &HashMap<&str, &Project>) -> Vec<String> {
+    let mut errors = Vec::new();
+    for project in projects {
+        for dependency in &project.depends_on {
+            if by_name.contains_key(dependency.as_str()) {
+                continue;
+            }
+            let names = projects.iter().map(|p| &p.name);
+            errors.push(match suggestion(dependency, names) {
+                Some(similar) => format!(
+                    "project {} depends on unknown project \"{}\", did you mean \"{}\"?",
+                    project.label(),
+                    dependency,
+                    similar
+                ),
+                None => format!(
+                    "project {} depends on unknown project \"{}\"",
+                    project.label(),
+                    dependency
+                ),
+            });
+        }
+    }
+    errors
+}
+
+/// Cycles reported at most, a densely connected config has exponentially many.
+const MAX_CYCLES: usize = 20;
+
+/// Follows the dependencies of `name` through projects ordered after `start` and records
+/// every path back to `start`. Each cycle is thereby found once, rotated to start at its
+/// first project, cycles over the same projects in a different order are separate cycles.
+fn find_cycles<'a>(
+    start: &'a str,
+    name: &'a str,
+    projects: &HashMap<&'a str, &'a Project>,
+    path: &mut Vec<&'a str>,
+    cycles: &mut Vec<Vec<&'a str>>,
+) {
+    path.push(name);
+    for dependency in &projects[name].depends_on {
+        if cycles.len() >= MAX_CYCLES {
+            break;
+        }
+        let Some((dependency, _)) = projects.get_key_value(dependency.as_str()) else {
+            continue;
+        };
+        if *dependency == start {
+            let mut cycle = path.clone();
+            cycle.push(start);
+            cycles.push(cycle);
+        } else if *dependency > start && !path.contains(dependency) {
+            find_cycles(start, dependency, projects, path, cycles);
+        }
+    }
+    path.pop();
+}
+
+/// Checks that every dependency exists and that `depends_on` forms a directed acyclic graph.
+pub fn validate_dependencies(projects: &[Project]) -> Vec<String> {
+    let by_name = projects
+        .iter()
+        .map(|p| (p.name.as_str(), p))
+        .collect::<HashMap<_, _>>();
+    let mut errors = unknown_dependencies(projects, &by_name);
+
+    let mut cycles = Vec::new();
+    for name in by_name.keys().copied().sorted() {
+        find_cycles(name, name, &by_name, &mut vec![], &mut cycles);
+    }
+    for cycle in cycles {
+        errors.push(format!("dependency cycle {}", cycle.join(" -> ")));
+    }
+    errors
+}
+
+/// The project and every project it transitively depends on.
+pub fn dependency_closure(projects: &[Project], name: &str) -> BTreeSet<SmolStr> {
+    let by_name = projects
+        .iter()
+        .map(|p| (p.name.as_str(), p))
+        .collect::<HashMap<_, _>>();
+    let mut closure = BTreeSet::new();
+    let mut stack = vec![name];
+    while let Some(current) = stack.pop() {
+        let Some(project) = by_name.get(current) else {
+            continue;
+        };
+        if closure.insert(project.name.clone()) {
+            stack.extend(project.depends_on.iter().map(|d| d.as_str()));
+        }
+    }
+    closure
+}
+
+/// Maps every selected project to the selected projects it transitively depends on,
+/// dependencies through projects that aren't selected are kept.
+pub fn selected_dependencies(
+    projects: &[Project],
+    selected: &BTreeSet<SmolStr>,
+) -> BTreeMap<SmolStr, BTreeSet<SmolStr>> {
+    selected
+        .iter()
+        .map(|name| {
+            let mut dependencies = dependency_closure(projects, name);
+            dependencies.remove(name);
+            dependencies.retain(|dependency| selected.contains(dependency));
+            (name.clone(), dependencies)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::test_util::projects;
+
+    #[test]
+    fn diamonds_are_valid() {
+        let projects = projects(
+            "- {name: app, depends_on: [api, web]}\n- {name: api, depends_on: [lib]}\n- {name: web, depends_on: [lib]}\n- {name: lib}",
+        );
+        assert!(validate_dependencies(&projects).is_empty());
+    }
+
+    #[test]
+    fn unknown_dependencies_suggest_similar_names() {
+        let projects = projects("- {name: app, depends_on: [libary, zzz]}\n- {name: library}");
+        let errors = validate_dependencies(&projects);
+        assert_eq!(errors.len(), 2, "{:?}", errors);
+        assert!(
+            errors[0].ends_with("did you mean \"library\"?"),
+            "{:?}",
+            errors
+        );
+        assert!(
+            errors[1].ends_with("unknown project \"zzz\""),
+            "{:?}",
+            errors
+        );
+    }
+
+    #[test]
+    fn each_cycle_is_reported_once() {
+        let chain = projects(
+            "- {name: a, depends_on: [b]}\n- {name: b, depends_on: [c]}\n- {name: c, depends_on: [a]}\n- {name: d, depends_on: [d, a]}",
+        );
+        let errors = validate_dependencies(&chain);
+        assert_eq!(
+            errors,
+            [
+                "dependency cycle a -> b -> c -> a",
+                "dependency cycle d -> d"
+            ]
+        );
+
+        // the same projects in a different order are a different cycle
+        let reordered = projects(
+            "- {name: a, depends_on: [b, c]}\n- {name: b, depends_on: [c, a]}\n- {name: c, depends_on: [a, b]}",
+        );
+        assert_eq!(
+            validate_dependencies(&reordered),
+            [
+                "dependency cycle a -> b -> c -> a",
+                "dependency cycle a -> b -> a",
+                "dependency cycle a -> c -> a",
+                "dependency cycle a -> c -> b -> a",
+                "dependency cycle b -> c -> b"
+            ]
+        );
+    }
+
+    #[test]
+    fn selected_projects_wait_for_selected_dependencies_only() {
+        let projects = projects(
+            "- {name: app, depends_on: [api]}\n- {name: api, depends_on: [lib]}\n- {name: lib}\n- {name: docs}",
+        );
+        assert_eq!(
+            dependency_closure(&projects, "app"),
+            BTreeSet::from(["app".into(), "api".into(), "lib".into()])
+        );
+
+        // api isn't selected, app still has to wait for lib
+        let selected = BTreeSet::from(["app".into(), "lib".into(), "docs".into()]);
+        let pending = selected_dependencies(&projects, &selected);
+        assert_eq!(
+            pending,
+            BTreeMap::from([
+                ("app".into(), BTreeSet::from(["lib".into()])),
+                ("docs".into(), BTreeSet::new()),
+                ("lib".into(), BTreeSet::new()),
+            ])
+        );
+    }
+}
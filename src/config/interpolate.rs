@@ -0,0 +1,238 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    env,
+};
+
+use anyhow::bail;
+use smol_str::SmolStr;
+
+use crate::config::{Config, project::Project};
+
+fn is_variable_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.')
+}
+
+/// Replaces every `${name}` with its value, `$${` stays a literal `${`.
+fn interpolate(value: &str, lookup: &impl Fn(&str) -> Option<String>) -> Result<String, String> {
+    let mut result = String::with_capacity(value.len());
+    let mut rest = value;
+    while let Some(start) = rest.find('$') {
+        result.push_str(&rest[..start]);
+        rest = &rest[start..];
+        if let Some(escaped) = rest.strip_prefix("$${") {
+            result.push_str("${");
+            rest = escaped;
+        } else if let Some(variable) = rest.strip_prefix("${") {
+            let end = variable
+                .find('}')
+                .ok_or_else(|| format!("unclosed \"${{\" in \"{}\"", value))?;
+            let name = &variable[..end];
+            if !is_variable_name(name) {
+                return Err(format!(
+                    "invalid variable \"${{{}}}\" in \"{}\", use \"$${{\" for a literal \"${{\"",
+                    name, value
+                ));
+            }
+            let resolved = lookup(name)
+                .ok_or_else(|| format!("undefined variable \"${{{}}}\" in \"{}\"", name, value))?;
+            result.push_str(&resolved);
+            rest = &variable[end + 1..];
+        } else {
+            result.push('$');
+            rest = &rest[1..];
+        }
+    }
+    result.push_str(rest);
+    Ok(result)
+}
+
+/// Replaces references to input groups by their globs, groups may reference other groups.
+fn expand_groups(
+    inputs: &[SmolStr],
+    groups: &BTreeMap<SmolStr, Vec<SmolStr>>,
+    expanding: &mut BTreeSet<SmolStr>,
+) -> Result<Vec<SmolStr>, String> {
+    let mut expanded = Vec::new();
+    for input in inputs {
+        let Some(group) = groups.get(input) else {
+            expanded.push(input.clone());
+            continue;
+        };
+        if !expanding.insert(input.clone()) {
+            return Err(format!("input group {} references itself", input));
+        }
+        expanded.extend(expand_groups(group, groups, expanding)?);
+        expanding.remove(input);
+    }
+    Ok(expanded)
+}
+
+fn resolve_project(
+    project: &mut Project,
+    groups: &BTreeMap<SmolStr, Vec<SmolStr>>,
+) -> Result<(), String> {
+    // globs are matched against paths without a leading "./"
+    let root = match project.root.trim_start_matches("./") {
+        "" => ".".to_owned(),
+        root => root.to_owned(),
+    };
+    let name = project.name.to_string();
+    let lookup = |variable: &str| match variable {
+        "project.root" => Some(root.clone()),
+        "project.name" => Some(name.clone()),
+        _ => env::var(variable).ok(),
+    };
+    // in globs the root matches itself, even if it contains `[`, `*` or `?`
+    let glob_lookup = |variable: &str| match variable {
+        "project.root" => Some(glob::Pattern::escape(&root)),
+        _ => lookup(variable),
+    };
+    let resolve = |values: &[SmolStr], expand: bool| -> Result<Vec<SmolStr>, String> {
+        let values = if expand {
+            expand_groups(values, groups, &mut BTreeSet::new())?
+        } else {
+            values.to_vec()
+        };
+        values
+            .iter()
+            .map(|value| interpolate(value, &glob_lookup).map(SmolStr::from))
+            .collect()
+    };
+    let resolve_env = |env: &mut BTreeMap<SmolStr, SmolStr>| -> Result<(), String> {
+        for value in env.values_mut() {
+            *value = interpolate(value, &lookup)?.into();
+        }
+        Ok(())
+    };
+    let resolve_workdir = |workdir: &mut Option<SmolStr>| -> Result<(), String> {
+        if let Some(workdir) = workdir {
+            *workdir = interpolate(workdir, &lookup)?.into();
+        }
+        Ok(())
+    };
+    project.inputs = resolve(&project.inputs, true)?;
+    project.outputs = resolve(&project.outputs, false)?;
+    resolve_env(&mut project.env)?;
+    resolve_workdir(&mut project.workdir)?;
+    for task in project.tasks.values_mut() {
+        task.command = interpolate(&task.command, &lookup)?.into();
+        resolve_env(&mut task.env)?;
+        resolve_workdir(&mut task.workdir)?;
+        task.inputs = resolve(&task.inputs, true)?;
+        task.outputs = resolve(&task.outputs, false)?;
+    }
+    Ok(())
+}
+
+/// Expands input groups and interpolates `${VAR}`, `${project.root}` and `${project.name}`
+/// in the globs, `env` values, working directories and task commands of every project.
+pub fn resolve_variables(config: &mut Config) -> anyhow::Result<()> {
+    let mut errors = Vec::new();
+    for project in &mut config.projects {
+        if let Err(e) = resolve_project(project, &config.input_groups) {
+            errors.push(format!("project {}: {}", project.label(), e));
+        }
+    }
+    if !errors.is_empty() {
+        bail!("Invalid project configuration:\n  {}", errors.join("\n  "));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::test_util::projects;
+
+    fn lookup(name: &str) -> Option<String> {
+        match name {
+            "NAME" => Some("api".to_owned()),
+            "project.root" => Some("svc/api".to_owned()),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn replaces_variables_and_keeps_escapes() {
+        assert_eq!(
+            interpolate("${project.root}/${NAME}-$${HOME}-$5", &lookup).unwrap(),
+            "svc/api/api-${HOME}-$5"
+        );
+        assert_eq!(
+            interpolate("no variables", &lookup).unwrap(),
+            "no variables"
+        );
+    }
+
+    #[test]
+    fn rejects_unclosed_invalid_and_undefined_variables() {
+        let error = |value| interpolate(value, &lookup).unwrap_err();
+        assert!(error("${NAME").starts_with("unclosed"));
+        assert!(error("${1x}").starts_with("invalid variable"));
+        assert!(error("${}").starts_with("invalid variable"));
+        assert!(error("${MISSING}").starts_with("undefined variable"));
+    }
+
+    #[test]
+    fn expands_nested_groups_and_rejects_cycles() {
+        let groups = BTreeMap::from([
+            ("rust".into(), vec!["src/**/*.rs".into(), "manifest".into()]),
+            ("manifest".into(), vec!["Cargo.toml".into()]),
+            ("loop".into(), vec!["loop".into()]),
+        ]);
+        let inputs = ["rust".into(), "build.rs".into()];
+        assert_eq!(
+            expand_groups(&inputs, &groups, &mut BTreeSet::new()).unwrap(),
+            ["src/**/*.rs", "Cargo.toml", "build.rs"]
+        );
+        assert!(expand_groups(&["loop".into()], &groups, &mut BTreeSet::new()).is_err());
+    }
+
+    #[test]
+    fn project_variables_use_the_root_without_leading_dot() {
+        let projects = serde_yml::from_str(
+            "- {name: api, root: ./svc/api, inputs: [\"${project.root}/src/*\"], workdir: \"${project.name}\"}\n- {name: top, inputs: [\"${project.root}/*.txt\"]}",
+        )
+        .unwrap();
+        let mut config = Config {
+            projects,
+            ..Default::default()
+        };
+        resolve_variables(&mut config).unwrap();
+        assert_eq!(config.projects[0].inputs, ["svc/api/src/*"]);
+        assert_eq!(config.projects[0].workdir.as_deref(), Some("api"));
+        assert_eq!(config.projects[1].inputs, ["./*.txt"]);
+    }
+
+    #[test]
+    fn project_root_is_escaped_in_globs_only() {
+        let mut config = Config {
+            projects: projects(
+                r#"
+- name: api
+  root: "svc/[api]"
+  inputs: ["${project.root}/src/*"]
+  outputs: ["${project.root}/out"]
+  env: {ROOT: "${project.root}"}
+  tasks:
+    list: {command: "ls ${project.root}", workdir: "${project.root}/src"}
+"#,
+            ),
+            ..Default::default()
+        };
+        resolve_variables(&mut config).unwrap();
+        let api = &config.projects[0];
+        assert_eq!(api.inputs, ["svc/[[]api[]]/src/*"]);
+        assert_eq!(api.outputs, ["svc/[[]api[]]/out"]);
+        assert_eq!(api.env["ROOT"], "svc/[api]");
+        assert_eq!(api.tasks["list"].command, "ls svc/[api]");
+        assert_eq!(api.tasks["list"].workdir.as_deref(), Some("svc/[api]/src"));
+        let input = glob::Pattern::new(&api.inputs[0]).unwrap();
+        assert!(input.matches("svc/[api]/src/main.rs"));
+        assert!(!input.matches("svc/a/src/main.rs"));
+    }
+}
@@ -1,63 +1,121 @@
+pub mod affected;
+pub mod check;
+pub mod executor;
+pub mod graph;
+pub mod interpolate;
 pub mod parse;
 pub mod project;
+#[cfg(test)]
+pub(crate) mod test_util;
 
-use anyhow::Context;
+use anyhow::{Context, anyhow, bail};
+use blake3::Hash;
+use itertools::Itertools;
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 use smol_str::SmolStr;
 use std::{
-    collections::BTreeMap,
+    collections::{BTreeMap, HashSet},
     env, fs,
     path::{Path, PathBuf},
     vec::Vec,
 };
 use tracing::{debug, info, trace};
 
-use crate::{config::project::Project, env_config::EnvConfig};
-
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub enum ExecutionEnvironment {
-    BASH,
-    SHELL,
-}
+use crate::{
+    cache::{
+        chunk::ChunkingConfig,
+        command::CachedCommand,
+        compression::{CompressionPolicy, CompressionRule, CompressionSetting},
+        encryption::EncryptionConfig,
+        folder::CacheFolder,
+        glob::get_paths_from_globs,
+        signing::SigningConfig,
+    },
+    config::{
+        check::validate_projects,
+        executor::Executor,
+        graph::{suggestion, validate_dependencies},
+        project::{DependencyInputs, Project, Task},
+    },
+    env_config::EnvConfig,
+};
 
-impl AsRef<str> for ExecutionEnvironment {
-    fn as_ref(&self) -> &str {
-        match &self {
-            ExecutionEnvironment::BASH => "bash",
-            ExecutionEnvironment::SHELL => "shell",
-        }
-    }
-}
+/// Config file names looked for in the cwd and its parents, the format follows the extension.
+pub const CONFIG_FILE_NAMES: [&str; 4] = ["cache.yml", "cache.yaml", "cache.toml", "cache.json"];
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
 pub struct Config {
-    pub exec: ExecutionEnvironment,
+    /// How commands are run, `bash -c` if omitted.
+    #[serde(default)]
+    pub exec: Executor,
+    #[serde(default)]
     pub projects: Vec<Project>,
+    /// Globs of project files, each defining one project relative to its own folder.
+    #[serde(default)]
+    #[schemars(with = "Vec<String>")]
+    pub include: Vec<SmolStr>,
+    /// Named lists of input globs, a project input with the name of a group is replaced by it.
+    #[serde(default)]
+    #[schemars(with = "BTreeMap<String, Vec<String>>")]
+    pub input_groups: BTreeMap<SmolStr, Vec<SmolStr>>,
+    #[schemars(with = "String")]
     pub cache_dir: SmolStr,
     pub ttl: u64,
+    #[serde(default)]
+    pub default_compression: Option<CompressionSetting>,
+    #[serde(default)]
+    pub compression: Vec<CompressionRule>,
+    #[serde(default)]
+    pub min_compression_savings: Option<u8>,
+    #[serde(default)]
+    pub chunking: Option<ChunkingConfig>,
+    #[serde(default)]
+    pub signing: Option<SigningConfig>,
+    #[serde(default)]
+    pub encryption: Option<EncryptionConfig>,
+    /// The config file, relative to its folder.
+    #[serde(skip)]
+    pub source: Option<SmolStr>,
 }
 
 impl Default for Config {
     fn default() -> Self {
         Self {
-            exec: ExecutionEnvironment::BASH,
+            exec: Executor::default(),
             projects: Default::default(),
+            include: Default::default(),
+            input_groups: Default::default(),
             cache_dir: ".cache".into(),
             ttl: 7,
+            default_compression: None,
+            compression: Default::default(),
+            min_compression_savings: None,
+            chunking: None,
+            signing: None,
+            encryption: None,
+            source: None,
         }
     }
 }
 
 impl Config {
     pub fn discover_file(env_config: &EnvConfig) -> anyhow::Result<Option<PathBuf>> {
+        let file_names = match &env_config.config_file_name {
+            Some(name) => vec![name.as_str()],
+            None => CONFIG_FILE_NAMES.to_vec(),
+        };
         let mut cwd = env::current_dir()?;
         let mut should_continue = true;
         while should_continue {
-            let config_file_path = cwd.join(env_config.config_file_name.as_str());
-            debug!("checking for config in {:?}", config_file_path);
-            if fs::exists(&config_file_path)? {
-                info!("Using configuration file {:?}", config_file_path);
-                return Ok(Some(config_file_path.canonicalize()?));
+            for file_name in &file_names {
+                let config_file_path = cwd.join(file_name);
+                debug!("checking for config in {:?}", config_file_path);
+                if fs::exists(&config_file_path)? {
+                    info!("Using configuration file {:?}", config_file_path);
+                    return Ok(Some(config_file_path.canonicalize()?));
+                }
             }
 
             should_continue = cwd.pop();
@@ -65,12 +123,13 @@ impl Config {
         info!("Could not find configuration");
         Ok(None)
     }
+    /// Picks the env vars listed by the selected project, other env vars don't affect the hash.
+    /// The static `env` of the project is added, it replaces inherited values.
     pub fn filter_env_vars(
         &self,
         env: &BTreeMap<String, String>,
-        root_path: &Path,
-    ) -> anyhow::Result<BTreeMap<String, String>> {
-        let project = self.get_project_for_cwd(root_path)?;
+        project: Option<&Project>,
+    ) -> BTreeMap<String, String> {
         let mut result = BTreeMap::new();
 
         if let Some(project) = project {
@@ -80,46 +139,88 @@ impl Config {
                     result.insert(env_var_name.to_string(), value.into());
                 }
             }
+            for (name, value) in &project.env {
+                result.insert(name.to_string(), value.to_string());
+            }
+        }
+        result
+    }
+
+    pub fn compression_policy(&self, project: Option<&Project>, root: &Path) -> CompressionPolicy {
+        CompressionPolicy {
+            root: root.canonicalize().unwrap_or(root.to_owned()),
+            rules: self.compression.clone(),
+            default_compression: project
+                .and_then(|p| p.default_compression)
+                .or(self.default_compression),
+            min_savings_percent: self.min_compression_savings,
+            dictionary: None,
+            chunking: self.chunking.clone(),
         }
-        Ok(result)
     }
 
     pub fn get_project(&self, id: &str) -> Option<&Project> {
-        self.projects.iter().find(|p| &p.name == id)
+        self.projects.iter().find(|p| p.name == id)
     }
 
+    /// The project with the deepest root containing the cwd, so nested projects win over
+    /// the ones around them regardless of their order in the config.
     pub fn get_project_for_cwd(&self, root: &Path) -> anyhow::Result<Option<&Project>> {
         let cwd = env::current_dir()?
             .canonicalize()
-            .with_context(|| format!("Could not canonicalize cwd"))?;
+            .context("Could not canonicalize cwd")?;
+        let mut best: Option<(&Project, usize)> = None;
         for project in &self.projects {
             let project_path = root.join(project.root.as_str());
             let project_path = project_path
                 .canonicalize()
                 .with_context(|| format!("Could not canonicalize {:?}", project_path))?;
-            if cwd.starts_with(&project_path) {
-                trace!(
-                    "Checking if project path {:?} is in {:?}",
-                    &project_path, cwd
-                );
-                return Ok(Some(project));
+            trace!(
+                "Checking if project path {:?} is in {:?}",
+                &project_path, cwd
+            );
+            let depth = project_path.components().count();
+            if cwd.starts_with(&project_path) && best.is_none_or(|(_, best)| depth > best) {
+                best = Some((project, depth));
             }
         }
-        return Ok(None);
+        Ok(best.map(|(project, _)| project))
+    }
+
+    /// Checks the project definitions, all problems are reported at once.
+    pub fn validate(&self, root: &Path) -> anyhow::Result<()> {
+        let mut errors = validate_projects(&self.projects, root);
+        errors.extend(validate_dependencies(&self.projects));
+        if let Some(chunking) = &self.chunking {
+            errors.extend(
+                chunking
+                    .problems()
+                    .into_iter()
+                    .map(|p| format!("chunking: {}", p)),
+            );
+        }
+        if !errors.is_empty() {
+            bail!("Invalid configuration:\n  {}", errors.join("\n  "));
+        }
+        Ok(())
     }
 
     pub fn get_all_depenend_file_globs(&self, project: &Project) -> anyhow::Result<Vec<SmolStr>> {
         let mut projects = Vec::new();
+        let mut visited = HashSet::new();
         let mut inputs = Vec::new();
         projects.push(project);
         while let Some(current_project) = projects.pop() {
+            if !visited.insert(&current_project.name) {
+                continue;
+            }
             // add depenent projects
             current_project
                 .depends_on
                 .iter()
                 .filter_map(|id| self.get_project(id))
                 .for_each(|p| {
-                    debug!("Found dependency {}", current_project.name.as_str());
+                    debug!("Found dependency {}", p.name.as_str());
                     projects.push(p);
                 });
             debug!(
@@ -132,4 +233,277 @@ impl Config {
         inputs.dedup();
         Ok(inputs)
     }
+
+    /// Like [`Self::get_project`], but an unknown name is an error suggesting a similar one.
+    pub fn require_project(&self, name: &str) -> anyhow::Result<&Project> {
+        self.get_project(name).ok_or_else(|| {
+            let names = self.projects.iter().map(|p| &p.name);
+            match suggestion(name, names) {
+                Some(similar) => anyhow!(
+                    "Unknown project \"{}\", did you mean \"{}\"?",
+                    name,
+                    similar
+                ),
+                None => anyhow!("Unknown project \"{}\"", name),
+            }
+        })
+    }
+
+    /// Finds a project and one of its tasks from `project:task`, or just `task` of the
+    /// selected project.
+    pub fn resolve_task(
+        &self,
+        target: &str,
+        selected: Option<&Project>,
+    ) -> anyhow::Result<(Project, SmolStr, Task)> {
+        let (project, name) = match target.split_once(':') {
+            Some((project_name, name)) => (self.require_project(project_name)?, name),
+            None => {
+                let project = selected.ok_or_else(|| {
+                    anyhow!(
+                        "No project selected for task \"{}\", use project:task or -p",
+                        target
+                    )
+                })?;
+                (project, target)
+            }
+        };
+        let Some((extended, task)) = project.with_task(name) else {
+            match suggestion(name, project.tasks.keys()) {
+                Some(similar) => bail!(
+                    "Project {} has no task \"{}\", did you mean \"{}\"?",
+                    project.name,
+                    name,
+                    similar
+                ),
+                None => bail!(
+                    "Project {} has no task \"{}\", available tasks: {}",
+                    project.name,
+                    name,
+                    project.tasks.keys().join(", ")
+                ),
+            }
+        };
+        let task = task.clone();
+        Ok((extended, name.into(), task))
+    }
+
+    /// What identifies a command in its hash besides its inputs and env vars. Tasks of
+    /// different projects with the same command line store different outputs so the task and
+    /// its definition are part of it, like the working directory of the project.
+    pub fn command_key(
+        &self,
+        project: Option<&Project>,
+        task: Option<&str>,
+        command_line: &str,
+    ) -> anyhow::Result<String> {
+        // the same command line means something else under another executor
+        let mut key = format!("exec {}\n", self.exec);
+        if let (Some(project), Some(task)) = (project, task) {
+            key.push_str(&format!("{}:{}\n", project.name, task));
+            // e.g. other outputs must not restore a record with the old ones
+            if let Some(definition) = project.tasks.get(task) {
+                key.push_str(&serde_json::to_string(definition)?);
+                key.push('\n');
+            }
+        }
+        if let Some(project) = project {
+            key.push_str(&format!(
+                "workdir {}\n",
+                project.workdir().to_string_lossy()
+            ));
+        }
+        key.push_str(command_line);
+        Ok(key)
+    }
+
+    /// Hashes a command line with the current inputs and env vars of the project (or task),
+    /// returns the hash and the env vars that went into it.
+    pub fn command_hash(
+        &self,
+        command_line: &str,
+        project: Option<&Project>,
+        task: Option<&str>,
+        root: &Path,
+        cache_folder: &CacheFolder,
+        env: &BTreeMap<String, String>,
+    ) -> anyhow::Result<(Hash, BTreeMap<String, String>)> {
+        let inputs = match project {
+            Some(project) => self.project_inputs(project, task, root, cache_folder, env)?,
+            None => ProjectInputs::default(),
+        };
+        let paths = get_paths_from_globs(&inputs.globs, root)
+            .into_iter()
+            .unique()
+            .collect();
+        let filtered_env = self.filter_env_vars(env, project);
+        let hash = CachedCommand::create_hash(
+            &self.command_key(project, task, command_line)?,
+            paths,
+            &filtered_env,
+            &inputs.dependency_outputs,
+        )?;
+        Ok((hash, filtered_env))
+    }
+
+    /// Output hashes of the cached command of a dependency's task for its current inputs,
+    /// `None` if that state was never cached. Plain commands aren't part of the config, the
+    /// ones cached for the dependency before are tried, newest first.
+    fn current_outputs(
+        &self,
+        dependency: &Project,
+        task: Option<&str>,
+        root: &Path,
+        cache_folder: &CacheFolder,
+        env: &BTreeMap<String, String>,
+    ) -> anyhow::Result<Option<Vec<SmolStr>>> {
+        let (project, command_lines) = match task {
+            Some(task) => {
+                let Some((project, definition)) = dependency.with_task(task) else {
+                    return Ok(None);
+                };
+                let argv = self.exec.task_argv(&definition.command)?;
+                let command_line = self.exec.command_line(&argv)?;
+                (project, vec![command_line])
+            }
+            None => {
+                let command_lines = cache_folder
+                    .plain_command_lines(&dependency.name)?
+                    .into_iter()
+                    .map(|line| line.to_string())
+                    .collect();
+                (dependency.clone(), command_lines)
+            }
+        };
+        for command_line in command_lines {
+            let (hash, _) =
+                self.command_hash(&command_line, Some(&project), task, root, cache_folder, env)?;
+            if !cache_folder.has_cached_command(&hash) {
+                debug!("Dependency {} has no cached command {}", project.name, hash);
+                continue;
+            }
+            let command = match cache_folder.get_cashed_command(&hash) {
+                Ok(command) => command,
+                Err(e) => {
+                    debug!("Ignoring {} for dependency outputs: {:#}", hash, e);
+                    continue;
+                }
+            };
+            if let Some(signing) = &self.signing
+                && let Err(e) = signing.verify(&command)
+            {
+                debug!("Ignoring {} for dependency outputs: {:#}", command.hash, e);
+                continue;
+            }
+            let mut hashes = command
+                .output_files
+                .into_iter()
+                .map(|output| output.hash)
+                .collect::<Vec<_>>();
+            hashes.sort();
+            return Ok(Some(hashes));
+        }
+        Ok(None)
+    }
+
+    /// Input globs of the project and, with [`DependencyInputs::Outputs`], the cached outputs of
+    /// the same task of its direct dependencies. Dependencies whose current state was never
+    /// cached contribute their inputs.
+    pub fn project_inputs(
+        &self,
+        project: &Project,
+        task: Option<&str>,
+        root: &Path,
+        cache_folder: &CacheFolder,
+        env: &BTreeMap<String, String>,
+    ) -> anyhow::Result<ProjectInputs> {
+        if project.dependency_inputs == DependencyInputs::Inputs {
+            return Ok(ProjectInputs {
+                globs: self.get_all_depenend_file_globs(project)?,
+                dependency_outputs: BTreeMap::new(),
+            });
+        }
+        let mut inputs = ProjectInputs {
+            globs: project.inputs.clone(),
+            dependency_outputs: BTreeMap::new(),
+        };
+        for dependency in project
+            .depends_on
+            .iter()
+            .filter_map(|id| self.get_project(id))
+        {
+            match self.current_outputs(dependency, task, root, cache_folder, env)? {
+                Some(outputs) => {
+                    debug!(
+                        "Adding {} cached outputs of dependency {}",
+                        outputs.len(),
+                        dependency.name
+                    );
+                    inputs
+                        .dependency_outputs
+                        .insert(dependency.name.clone(), outputs);
+                }
+                None => {
+                    info!(
+                        "Dependency {} has no cached outputs for its current inputs, using them",
+                        dependency.name
+                    );
+                    inputs
+                        .globs
+                        .extend(self.get_all_depenend_file_globs(dependency)?);
+                }
+            }
+        }
+        inputs.globs.dedup();
+        Ok(inputs)
+    }
+}
+
+/// Everything of a project that goes into the command key besides the command and env vars.
+#[derive(Debug, Default)]
+pub struct ProjectInputs {
+    pub globs: Vec<SmolStr>,
+    /// Sorted output hashes by dependency name.
+    pub dependency_outputs: BTreeMap<SmolStr, Vec<SmolStr>>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::test_util::projects;
+
+    #[test]
+    fn only_env_vars_of_the_project_are_kept() {
+        let projects = projects(
+            r#"
+- name: api
+  envs: [API_TOKEN, MISSING]
+  env: {MODE: release, API_TOKEN: fixed}
+- name: web
+  envs: [NODE_ENV]
+"#,
+        );
+        let env = BTreeMap::from(
+            [
+                ("API_TOKEN", "secret"),
+                ("NODE_ENV", "production"),
+                ("HOME", "/root"),
+            ]
+            .map(|(name, value)| (name.to_owned(), value.to_owned())),
+        );
+        let config = Config::default();
+        let filtered = |project| {
+            config
+                .filter_env_vars(&env, project)
+                .into_iter()
+                .map(|(name, value)| format!("{}={}", name, value))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            filtered(Some(&projects[0])),
+            ["API_TOKEN=fixed", "MODE=release"]
+        );
+        assert_eq!(filtered(Some(&projects[1])), ["NODE_ENV=production"]);
+        assert!(filtered(None).is_empty());
+    }
 }
@@ -1,23 +1,466 @@
-use std::{fs, path::Path};
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::{Component, Path, PathBuf},
+};
 
-use crate::config::Config;
-use anyhow::Context;
-use smol_str::SmolStr;
+use crate::config::{Config, interpolate::resolve_variables, project::Project};
+use anyhow::{Context, bail};
+use serde::de::DeserializeOwned;
+use smol_str::{SmolStr, ToSmolStr};
+use tracing::debug;
+
+/// Conventional names of a project file, picked up through `include` in the config.
+pub const PROJECT_FILE_NAMES: [&str; 4] = [
+    "cache.project.yml",
+    "cache.project.yaml",
+    "cache.project.toml",
+    "cache.project.json",
+];
+
+/// Parses a yaml, toml or json file depending on its extension, the error names the file
+/// and the line of the problem. The content is returned as well.
+fn read_file<T: DeserializeOwned>(path: &Path) -> anyhow::Result<(T, String)> {
+    let content = fs::read_to_string(path).with_context(|| format!("Could not read {:?}", path))?;
+    let parsed = match path.extension().and_then(|e| e.to_str()) {
+        Some("toml") => toml::from_str(&content).map_err(anyhow::Error::from),
+        Some("json") => serde_json::from_str(&content).map_err(anyhow::Error::from),
+        _ => serde_yml::from_str(&content).map_err(anyhow::Error::from),
+    };
+    let parsed = parsed.with_context(|| format!("Could not parse {:?}", path))?;
+    Ok((parsed, content))
+}
+
+/// Sets the line of the `name` key of each project, projects are found in file order.
+/// Works for all three formats, e.g. `name: api`, `- {name: api}`, `name = "api"` or
+/// `"name": "api"`.
+fn locate_projects<'a>(projects: impl Iterator<Item = &'a mut Project>, content: &str) {
+    let mut lines = content.lines().enumerate();
+    for project in projects {
+        project.line = lines
+            .find(|(_, line)| names_project(line, &project.name))
+            .map(|(index, _)| index + 1);
+    }
+}
+
+/// Whether `line` has a `name` key with the value `name`. Only keys count, i.e. `name` at the
+/// start of the line, after `- `, `{` or `,`, but not inside quoted values or after a `#`.
+fn names_project(line: &str, name: &str) -> bool {
+    let mut quote = None;
+    let mut previous = None;
+    for (index, c) in line.char_indices() {
+        if let Some(open) = quote {
+            if c == open {
+                quote = None;
+                previous = Some(c);
+            }
+            continue;
+        }
+        if c == '#' {
+            return false;
+        }
+        if previous.is_none_or(|p| "-{,".contains(p)) && is_name_key(&line[index..], name) {
+            return true;
+        }
+        if c == '"' || c == '\'' {
+            quote = Some(c);
+        }
+        if !c.is_whitespace() {
+            previous = Some(c);
+        }
+    }
+    false
+}
+
+/// Whether `rest` starts with `name: <name>`, `name = "<name>"` or `"name": "<name>"`.
+fn is_name_key(rest: &str, name: &str) -> bool {
+    let key = rest
+        .strip_prefix("\"name\"")
+        .or_else(|| rest.strip_prefix("name"));
+    let Some(value) = key.map(str::trim_start) else {
+        return false;
+    };
+    let Some(value) = value.strip_prefix([':', '=']) else {
+        return false;
+    };
+    let value = value.trim_start().trim_start_matches(['"', '\'']);
+    value
+        .strip_prefix(name)
+        .is_some_and(|rest| !rest.starts_with(|c: char| c.is_alphanumeric() || "_-.".contains(c)))
+}
+
+fn prefixed(dir: &str, globs: &mut [SmolStr], groups: &BTreeMap<SmolStr, Vec<SmolStr>>) {
+    if dir.is_empty() {
+        return;
+    }
+    // input groups are relative to the config file, `${project.root}` is the rebased root
+    let dir = glob::Pattern::escape(dir);
+    for glob in globs
+        .iter_mut()
+        .filter(|glob| !groups.contains_key(*glob) && !glob.starts_with("${project.root}"))
+    {
+        *glob = format!("{}/{}", dir, glob).into();
+    }
+}
+
+/// Makes the root and globs of a project from a project file relative to the config root.
+fn rebase(project: &mut Project, dir: &Path, groups: &BTreeMap<SmolStr, Vec<SmolStr>>) {
+    let dir = dir.to_string_lossy();
+    project.root = if project.root.is_empty() {
+        format!("./{}", dir).into()
+    } else {
+        format!("./{}/{}", dir, project.root).into()
+    };
+    prefixed(&dir, &mut project.inputs, groups);
+    prefixed(&dir, &mut project.outputs, &BTreeMap::new());
+    for task in project.tasks.values_mut() {
+        prefixed(&dir, &mut task.inputs, groups);
+        prefixed(&dir, &mut task.outputs, &BTreeMap::new());
+    }
+}
+
+/// Adds the projects of every file matching the `include` globs, relative to `root`.
+/// For matching folders their project file (see [`PROJECT_FILE_NAMES`]) is included.
+fn include_projects(config: &mut Config, root: &Path) -> anyhow::Result<()> {
+    let root = root.canonicalize()?;
+    for pattern in &config.include {
+        let full_pattern = format!(
+            "{}/{}",
+            glob::Pattern::escape(&root.to_string_lossy()),
+            pattern
+        );
+        let mut paths = glob::glob(&full_pattern)
+            .with_context(|| format!("Invalid include pattern \"{}\"", pattern))?
+            .map(|path| {
+                // a matched folder contributes its project file
+                let path = path?;
+                if !path.is_dir() {
+                    return Ok(path);
+                }
+                let project_file = PROJECT_FILE_NAMES
+                    .iter()
+                    .map(|name| path.join(name))
+                    .find(|file| file.is_file());
+                Ok(project_file.unwrap_or(path))
+            })
+            .filter(|path: &anyhow::Result<PathBuf>| !matches!(path, Ok(p) if !p.is_file()))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        paths.sort();
+        if paths.is_empty() {
+            debug!("Include {} matched no project files", pattern);
+        }
+        for path in paths {
+            let relative = path.strip_prefix(&root)?.to_owned();
+            let dir = relative.parent().unwrap_or(Path::new(""));
+            if dir.components().any(|c| c == Component::ParentDir) {
+                bail!("Included project file {:?} is outside of {:?}", path, root);
+            }
+            let (mut project, content): (Project, _) = read_file(&path)?;
+            locate_projects(std::iter::once(&mut project), &content);
+            rebase(&mut project, dir, &config.input_groups);
+            project.source = Some(relative.to_string_lossy().to_smolstr());
+            debug!("Included project {}", project.label());
+            config.projects.push(project);
+        }
+    }
+    Ok(())
+}
 
 pub fn parse_config_file(
     path: &Path,
     cache_dir_override: Option<SmolStr>,
 ) -> anyhow::Result<Config> {
     if path.exists() {
-        let yaml =
-            fs::read_to_string(path).with_context(|| format!("Could not read {:?}", path))?;
-        let mut config: Config = serde_yml::from_str(&yaml)
-            .with_context(|| format!("Could not parse config yaml for {:?}", path))?;
+        let (mut config, content): (Config, _) = read_file(path)?;
         if let Some(cache_dir) = cache_dir_override {
             config.cache_dir = cache_dir;
         }
+        config.source = path
+            .file_name()
+            .map(|name| name.to_string_lossy().to_smolstr());
+        locate_projects(config.projects.iter_mut(), &content);
+        for project in &mut config.projects {
+            project.source = config.source.clone();
+        }
+        let root = path
+            .parent()
+            .filter(|parent| !parent.as_os_str().is_empty())
+            .unwrap_or(Path::new("."));
+        include_projects(&mut config, root)?;
+        resolve_variables(&mut config)?;
         Ok(config)
     } else {
         Ok(Config::default())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+
+    use super::*;
+    use crate::config::affected::affected_projects;
+
+    #[test]
+    fn project_root_variable_is_not_prefixed_twice() {
+        let dir = tempfile::tempdir().unwrap();
+        let project_dir = dir.path().join("svc/api");
+        fs::create_dir_all(project_dir.join("src")).unwrap();
+        fs::write(
+            dir.path().join("cache.yml"),
+            "cache_dir: .cache\nttl: 7\ninclude: [svc/*]\n",
+        )
+        .unwrap();
+        fs::write(
+            project_dir.join("cache.project.yml"),
+            "name: api\ninputs: [\"${project.root}/src/*\", \"lib/*\"]\n",
+        )
+        .unwrap();
+
+        let config = parse_config_file(&dir.path().join("cache.yml"), None).unwrap();
+        let api = config.get_project("api").unwrap();
+        assert_eq!(api.inputs, ["svc/api/src/*", "svc/api/lib/*"]);
+
+        let changed = BTreeSet::from([PathBuf::from("svc/api/src/a.txt")]);
+        let affected = affected_projects(&config, &changed).unwrap();
+        assert_eq!(affected, BTreeSet::from(["api".into()]));
+    }
+
+    #[test]
+    fn project_folders_are_escaped_in_globs() {
+        let dir = tempfile::tempdir().unwrap();
+        let project_dir = dir.path().join("svc/[x]");
+        fs::create_dir_all(&project_dir).unwrap();
+        fs::write(
+            dir.path().join("cache.yml"),
+            "cache_dir: .cache\nttl: 7\ninclude: [svc/*]\n",
+        )
+        .unwrap();
+        fs::write(
+            project_dir.join("cache.project.yml"),
+            "name: x\ninputs: [src/*]\n",
+        )
+        .unwrap();
+
+        let config = parse_config_file(&dir.path().join("cache.yml"), None).unwrap();
+        let x = config.get_project("x").unwrap();
+        assert_eq!(x.inputs, ["svc/[[]x[]]/src/*"]);
+
+        let changed = BTreeSet::from([PathBuf::from("svc/[x]/src/a.txt")]);
+        let affected = affected_projects(&config, &changed).unwrap();
+        assert_eq!(affected, BTreeSet::from(["x".into()]));
+    }
+
+    #[test]
+    fn projects_know_the_line_of_their_name() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir_all(dir.path().join("web")).unwrap();
+        fs::write(
+            dir.path().join("cache.yml"),
+            "cache_dir: .cache\nttl: 7\ninclude: [web]\nprojects:\n  - name: app\n    inputs: [app/*]\n  - {root: lib, name: lib}\n",
+        )
+        .unwrap();
+        fs::write(
+            dir.path().join("web/cache.project.toml"),
+            "inputs = [\"src/*\"]\nname = \"web\"\n",
+        )
+        .unwrap();
+
+        let config = parse_config_file(&dir.path().join("cache.yml"), None).unwrap();
+        let label = |name| config.get_project(name).unwrap().label();
+        assert_eq!(label("app"), "app (cache.yml:5)");
+        assert_eq!(label("lib"), "lib (cache.yml:7)");
+        assert_eq!(label("web"), "web (web/cache.project.toml:2)");
+    }
+
+    #[test]
+    fn names_in_comments_and_values_are_not_project_lines() {
+        let config = parse(
+            "cache.yml",
+            r#"
+cache_dir: .cache
+ttl: 7
+# name: api is defined below
+projects:
+  - name: web
+    env: {NOTE: "name: api"}
+    tasks:
+      build: >
+        echo name: api
+  - root: api
+    name: api
+"#,
+        )
+        .unwrap();
+        assert_eq!(
+            config.get_project("api").unwrap().label(),
+            "api (cache.yml:12)"
+        );
+
+        let config = parse(
+            "cache.json",
+            r#"{"cache_dir": ".cache", "ttl": 7, "projects": [
+  {"name": "web", "env": {"A": "{\"name\": \"api\"}"}},
+  {"root": "api", "name": "api"}
+]}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            config.get_project("api").unwrap().label(),
+            "api (cache.json:3)"
+        );
+    }
+
+    fn parse(file_name: &str, content: &str) -> anyhow::Result<Config> {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join(file_name), content).unwrap();
+        parse_config_file(&dir.path().join(file_name), None)
+    }
+
+    const TASKS: &str = r#"
+cache_dir: .cache
+ttl: 7
+projects:
+  - name: api
+    tasks:
+      short: cargo build
+      full:
+        command: cargo build
+      extended:
+        command: cargo test
+        inputs: [tests/*]
+        env: {RUST_LOG: debug}
+"#;
+
+    #[test]
+    fn tasks_are_a_command_or_a_table() {
+        let config = parse("cache.yml", TASKS).unwrap();
+        let tasks = &config.get_project("api").unwrap().tasks;
+        let json = |name: &str| serde_json::to_value(&tasks[name]).unwrap();
+        assert_eq!(json("short"), json("full"));
+        assert_eq!(tasks["extended"].inputs, ["tests/*"]);
+        assert_eq!(tasks["extended"].env["RUST_LOG"], "debug");
+
+        let error = parse(
+            "cache.yml",
+            "cache_dir: .cache\nttl: 7\nprojects:\n  - name: api\n    tasks:\n      t: {command: ls, input: [a]}\n",
+        )
+        .unwrap_err();
+        assert!(
+            format!("{:#}", error).contains("unknown field `input`"),
+            "{:#}",
+            error
+        );
+    }
+
+    #[test]
+    fn unknown_projects_and_tasks_suggest_similar_names() {
+        let config = parse("cache.yml", TASKS).unwrap();
+        let api = config.get_project("api");
+        let error = |target: &str, selected| {
+            config
+                .resolve_task(target, selected)
+                .unwrap_err()
+                .to_string()
+        };
+        assert_eq!(
+            error("apj:short", None),
+            "Unknown project \"apj\", did you mean \"api\"?"
+        );
+        assert_eq!(error("zzz:short", None), "Unknown project \"zzz\"");
+        assert!(error("fulll", api).ends_with("did you mean \"full\"?"));
+        assert_eq!(
+            error("zzz", api),
+            "Project api has no task \"zzz\", available tasks: extended, full, short"
+        );
+        assert!(error("short", None).starts_with("No project selected"));
+
+        let (project, name, task) = config.resolve_task("api:extended", None).unwrap();
+        assert_eq!((project.name.as_str(), name.as_str()), ("api", "extended"));
+        assert_eq!(task.command, "cargo test");
+        assert!(project.inputs.contains(&"tests/*".into()));
+    }
+
+    #[test]
+    fn formats_give_the_same_config() {
+        let yaml = r#"
+cache_dir: .cache
+ttl: 7
+default_compression: zstd-19
+compression:
+  - "**/*.png": none
+input_groups:
+  rust: ["**/*.rs", Cargo.toml]
+projects:
+  - name: api
+    root: api
+    inputs: [rust]
+    envs: [API_TOKEN]
+    tasks:
+      build: cargo build
+"#;
+        let toml = r#"
+cache_dir = ".cache"
+ttl = 7
+default_compression = "zstd-19"
+compression = [{ "**/*.png" = "none" }]
+
+[input_groups]
+rust = ["**/*.rs", "Cargo.toml"]
+
+[[projects]]
+name = "api"
+root = "api"
+inputs = ["rust"]
+envs = ["API_TOKEN"]
+tasks = { build = "cargo build" }
+"#;
+        let json = r#"{
+  "cache_dir": ".cache",
+  "ttl": 7,
+  "default_compression": "zstd-19",
+  "compression": [{"**/*.png": "none"}],
+  "input_groups": {"rust": ["**/*.rs", "Cargo.toml"]},
+  "projects": [{
+    "name": "api",
+    "root": "api",
+    "inputs": ["rust"],
+    "envs": ["API_TOKEN"],
+    "tasks": {"build": "cargo build"}
+  }]
+}"#;
+        let value = |file_name: &str, content: &str| {
+            serde_json::to_value(parse(file_name, content).unwrap()).unwrap()
+        };
+        let expected = value("cache.yml", yaml);
+        assert_eq!(expected["projects"][0]["inputs"][1], "Cargo.toml");
+        assert_eq!(value("cache.toml", toml), expected);
+        assert_eq!(value("cache.json", json), expected);
+    }
+
+    #[test]
+    fn unknown_fields_name_the_file_and_line() {
+        for (file_name, content, line) in [
+            (
+                "cache.yml",
+                "cache_dir: .cache\nttl: 7\nprojects:\n  - name: api\n    input: [src/*]\n",
+                "line 5",
+            ),
+            (
+                "cache.toml",
+                "cache_dir = \".cache\"\nttl = 7\n\n[[projects]]\nname = \"api\"\ninput = [\"src/*\"]\n",
+                "line 6",
+            ),
+            (
+                "cache.json",
+                "{\n  \"cache_dir\": \".cache\",\n  \"ttl\": 7,\n  \"tll\": 7\n}",
+                "line 4",
+            ),
+        ] {
+            let error = format!("{:#}", parse(file_name, content).unwrap_err());
+            assert!(error.contains(file_name), "{}", error);
+            assert!(error.contains("unknown field"), "{}", error);
+            assert!(error.contains(line), "{}", error);
+        }
+    }
+}
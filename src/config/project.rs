@@ -2,10 +2,15 @@ use anyhow::bail;
 use blake3::Hash;
 use itertools::Itertools;
 use nonempty::NonEmpty;
-use serde::{Deserialize, Serialize};
+use schemars::{JsonSchema, Schema, SchemaGenerator};
+use serde::{
+    Deserialize, Deserializer, Serialize,
+    de::{self, MapAccess, Visitor, value::MapAccessDeserializer},
+};
 use smol_str::{SmolStr, ToSmolStr};
 use std::{
-    collections::HashMap,
+    borrow::Cow,
+    collections::{BTreeMap, HashMap},
     path::{Path, PathBuf},
     sync::Arc,
 };
@@ -13,29 +18,221 @@ use tokio::{sync::Semaphore, task::JoinSet};
 use tracing::debug;
 
 use crate::cache::{
-    command::OutputFile, file::CachedFile, folder::CacheFolder, glob::get_paths_from_globs,
+    command::OutputFile,
+    compression::{CompressionPolicy, CompressionSetting},
+    file::CachedFile,
+    folder::CacheFolder,
+    glob::get_paths_from_globs,
 };
 
+/// What the projects in `depends_on` contribute to the command key.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum DependencyInputs {
+    /// The input files of the dependencies and their dependencies are hashed.
+    #[default]
+    Inputs,
+    /// The outputs of the latest cached command of each dependency are hashed.
+    Outputs,
+}
+
+/// A named command of a project, its inputs, outputs and env vars extend the project's.
+/// Written either as the command alone or as a map with `command`.
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(from = "TaskDefinition")]
+pub struct Task {
+    pub command: SmolStr,
+    pub envs: Vec<SmolStr>,
+    pub env: BTreeMap<SmolStr, SmolStr>,
+    pub workdir: Option<SmolStr>,
+    pub inputs: Vec<SmolStr>,
+    pub outputs: Vec<SmolStr>,
+}
+
+#[derive(Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+struct FullTask {
+    #[schemars(with = "String")]
+    command: SmolStr,
+    #[serde(default)]
+    #[schemars(with = "Vec<String>")]
+    envs: Vec<SmolStr>,
+    /// Added to the env of the project, replacing variables of the same name.
+    #[serde(default)]
+    #[schemars(with = "BTreeMap<String, String>")]
+    env: BTreeMap<SmolStr, SmolStr>,
+    /// Replaces the working directory of the project, relative to the project root.
+    #[serde(default)]
+    #[schemars(with = "Option<String>")]
+    workdir: Option<SmolStr>,
+    #[serde(default)]
+    #[schemars(with = "Vec<String>")]
+    inputs: Vec<SmolStr>,
+    #[serde(default)]
+    #[schemars(with = "Vec<String>")]
+    outputs: Vec<SmolStr>,
+}
+
+#[derive(JsonSchema)]
+#[serde(untagged)]
+enum TaskDefinition {
+    Command(#[schemars(with = "String")] SmolStr),
+    Full(FullTask),
+}
+
+/// `untagged` above only shapes the schema. Deserializing picks the variant by hand, so
+/// mistakes in a full task are reported instead of "no variant matched".
+impl<'de> Deserialize<'de> for TaskDefinition {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct DefinitionVisitor;
+
+        impl<'de> Visitor<'de> for DefinitionVisitor {
+            type Value = TaskDefinition;
+
+            fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+                formatter.write_str("a command or a map with command")
+            }
+
+            fn visit_str<E: de::Error>(self, command: &str) -> Result<Self::Value, E> {
+                Ok(TaskDefinition::Command(command.into()))
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, map: A) -> Result<Self::Value, A::Error> {
+                FullTask::deserialize(MapAccessDeserializer::new(map)).map(TaskDefinition::Full)
+            }
+        }
+
+        deserializer.deserialize_any(DefinitionVisitor)
+    }
+}
+
+impl JsonSchema for Task {
+    fn schema_name() -> Cow<'static, str> {
+        "Task".into()
+    }
+
+    fn json_schema(generator: &mut SchemaGenerator) -> Schema {
+        TaskDefinition::json_schema(generator)
+    }
+}
+
+impl From<TaskDefinition> for Task {
+    fn from(definition: TaskDefinition) -> Self {
+        match definition {
+            TaskDefinition::Command(command) => Task {
+                command,
+                envs: vec![],
+                env: BTreeMap::new(),
+                workdir: None,
+                inputs: vec![],
+                outputs: vec![],
+            },
+            TaskDefinition::Full(task) => Task {
+                command: task.command,
+                envs: task.envs,
+                env: task.env,
+                workdir: task.workdir,
+                inputs: task.inputs,
+                outputs: task.outputs,
+            },
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
 pub struct Project {
+    /// Relative to the file defining the project, that folder if empty.
+    #[serde(default)]
+    #[schemars(with = "String")]
     pub root: SmolStr,
     #[serde(default)]
+    #[schemars(with = "Vec<String>")]
     pub envs: Vec<SmolStr>,
+    /// Variables set for the commands of the project, they are part of the hash.
     #[serde(default)]
+    #[schemars(with = "BTreeMap<String, String>")]
+    pub env: BTreeMap<SmolStr, SmolStr>,
+    /// Where commands of the project run, relative to the project root, the root if omitted.
+    #[serde(default)]
+    #[schemars(with = "Option<String>")]
+    pub workdir: Option<SmolStr>,
+    #[serde(default)]
+    #[schemars(with = "Vec<String>")]
     pub inputs: Vec<SmolStr>,
     #[serde(default)]
+    #[schemars(with = "Vec<String>")]
     pub outputs: Vec<SmolStr>,
+    #[schemars(with = "String")]
     pub name: SmolStr,
     #[serde(default)]
+    #[schemars(with = "Vec<String>")]
     pub depends_on: Vec<SmolStr>,
+    #[serde(default)]
+    pub default_compression: Option<CompressionSetting>,
+    #[serde(default)]
+    pub dependency_inputs: DependencyInputs,
+    #[serde(default)]
+    #[schemars(with = "BTreeMap<String, Task>")]
+    pub tasks: BTreeMap<SmolStr, Task>,
+    /// The config or project file defining the project, relative to the config file.
+    #[serde(skip)]
+    pub source: Option<SmolStr>,
+    /// Line of the `name` of the project in `source`.
+    #[serde(skip)]
+    pub line: Option<usize>,
 }
 impl Project {
+    /// Name of the project for messages, with the file and line defining it.
+    pub fn label(&self) -> String {
+        match self.location() {
+            Some(location) => format!("{} ({})", self.name, location),
+            None => self.name.to_string(),
+        }
+    }
+
+    /// `file:line` of the project definition, if it was read from a file.
+    pub fn location(&self) -> Option<String> {
+        let source = self.source.as_ref()?;
+        Some(match self.line {
+            Some(line) => format!("{}:{}", source, line),
+            None => source.to_string(),
+        })
+    }
+
+    /// Folder the commands of the project run in, relative to the config root.
+    pub fn workdir(&self) -> PathBuf {
+        let root = match self.root.as_str() {
+            "" => Path::new("."),
+            root => Path::new(root),
+        };
+        match &self.workdir {
+            Some(workdir) => root.join(workdir.as_str()),
+            None => root.to_owned(),
+        }
+    }
+
+    /// The project extended by the inputs, outputs and env vars of one of its tasks.
+    pub fn with_task(&self, name: &str) -> Option<(Project, &Task)> {
+        let task = self.tasks.get(name)?;
+        let mut project = self.clone();
+        project.envs.extend(task.envs.iter().cloned());
+        project.env.extend(task.env.clone());
+        if task.workdir.is_some() {
+            project.workdir = task.workdir.clone();
+        }
+        project.inputs.extend(task.inputs.iter().cloned());
+        project.outputs.extend(task.outputs.iter().cloned());
+        Some((project, task))
+    }
+
     pub async fn gather_output_files(
         &self,
         root_folder: &Path,
         cache_folder: &CacheFolder,
+        policy: Arc<CompressionPolicy>,
     ) -> anyhow::Result<Vec<OutputFile>> {
-        let paths = get_paths_from_globs(&self.outputs, &root_folder)
+        let paths = get_paths_from_globs(&self.outputs, root_folder)
             .into_iter()
             .unique()
             .collect::<Vec<PathBuf>>();
@@ -67,8 +264,13 @@ impl Project {
 
         for (hash, (paths, size)) in output_path_map {
             let hash_string = hash.to_smolstr();
-            let future =
-                CachedFile::create(cache_folder.root.clone(), paths.first().clone(), hash, size);
+            let future = CachedFile::create(
+                cache_folder.root.clone(),
+                paths.first().clone(),
+                hash,
+                size,
+                policy.clone(),
+            );
             let clone = semaphore.clone();
             futures.spawn(async move {
                 let _token = clone.acquire().await?;
@@ -0,0 +1,8 @@
+//! Helpers shared by the tests of the config modules.
+
+use crate::config::project::Project;
+
+/// Projects from a YAML list, as written under `projects:` in the config.
+pub fn projects(yaml: &str) -> Vec<Project> {
+    serde_yml::from_str(yaml).unwrap()
+}
@@ -5,7 +5,8 @@ use tracing::debug;
 
 #[derive(Debug, Clone)]
 pub struct EnvConfig {
-    pub config_file_name: SmolStr,
+    /// Only this file name is discovered if set, otherwise any of the supported formats.
+    pub config_file_name: Option<SmolStr>,
     pub cache_dir: Option<SmolStr>,
     pub read_only: bool,
 }
@@ -13,11 +14,8 @@ pub fn parse_env() -> EnvConfig {
     let env_vars = env::vars().collect::<BTreeMap<String, String>>();
 
     debug!("From environment vars:");
-    let config_file_name = env_vars
-        .get("CACHE_CONFIG_FILE")
-        .map(|e| e.to_smolstr())
-        .unwrap_or("cache.yml".to_smolstr());
-    debug!("config_filename=\"{}\"", config_file_name);
+    let config_file_name = env_vars.get("CACHE_CONFIG_FILE").map(|e| e.to_smolstr());
+    debug!("config_filename=\"{:?}\"", config_file_name);
 
     let cache_dir = env_vars.get("CACHE_DIR").map(|e| e.to_smolstr());
     debug!("cache_dir=\"{:?}\"", cache_dir);
@@ -28,9 +26,9 @@ pub fn parse_env() -> EnvConfig {
         .unwrap_or(false);
     debug!("cache_readonly=\"{}\"", read_only);
 
-    return EnvConfig {
+    EnvConfig {
         read_only,
         cache_dir,
         config_file_name,
-    };
+    }
 }
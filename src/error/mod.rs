@@ -1,9 +1,18 @@
 use std::path::PathBuf;
 
+use smol_str::SmolStr;
 use thiserror::Error;
 
 #[derive(Error, Debug)]
 pub enum CacheError {
     #[error("Could not open path {0})")]
     OpenPathError(PathBuf),
+    #[error("Cached file {hash} is corrupt: {reason}")]
+    CorruptBlob { hash: SmolStr, reason: String },
+    #[error("{path:?} was written with the newer format version {version}")]
+    UnknownVersion { path: PathBuf, version: u32 },
+    #[error("Cached command {hash} is not trusted, {reason}")]
+    UntrustedCommand { hash: SmolStr, reason: String },
+    #[error("Cached entry {hash} is encrypted but no encryption key is set")]
+    MissingKey { hash: SmolStr },
 }
@@ -1,28 +1,47 @@
 use std::{
-    collections::BTreeMap,
+    collections::{BTreeMap, BTreeSet},
     env::{self},
     fs,
-    path::PathBuf,
+    path::{Path, PathBuf},
     process::Stdio,
+    sync::Arc,
 };
 
 use anyhow::{Context, anyhow, bail};
 use blake3::Hash;
-use chrono::Utc;
+use chrono::{DateTime, Utc};
 use clap::Parser;
 use itertools::Itertools;
 use nonempty::NonEmpty;
+use schemars::schema_for;
 use simple_ci_cache::{
     cache::{
-        command::CachedCommand, file::CachedFile, folder::CacheFolder, glob::get_paths_from_globs,
+        archive::{ExportFilter, export, import},
+        command::CachedCommand,
+        dictionary::Dictionary,
+        file::CachedFile,
+        folder::CacheFolder,
+        format::{CacheFormat, FORMAT_VERSION, migrate},
+        glob::get_paths_from_globs,
+        pack::repack,
+        signing::{generate_key_file, sign},
+        verify::verify,
+    },
+    cli::{Action, CommandLineArgs},
+    config::{
+        Config,
+        affected::{affected_projects, changed_files},
+        graph::{dependency_closure, selected_dependencies},
+        parse::parse_config_file,
+        project::Project,
     },
-    cli::CommandLineArgs,
-    config::{Config, parse::parse_config_file, project::Project},
     env_config::parse_env,
+    error::CacheError,
     standard_out::redirect_to_file_and_stdout,
 };
+use smol_str::SmolStr;
 use tokio::task::JoinSet;
-use tracing::{debug, info};
+use tracing::{debug, info, warn};
 use tracing_subscriber::{
     filter,
     fmt::{self},
@@ -35,22 +54,17 @@ async fn initialize(cli: &CommandLineArgs) -> anyhow::Result<(Config, PathBuf, P
     let env_config = parse_env();
     let config_path =
         Config::discover_file(&env_config).with_context(|| "Failed to discover config file")?;
-    let maybe_config_path = cli
-        .config
-        .as_ref()
-        .map(|c| PathBuf::from(c))
-        .or(config_path);
+    let maybe_config_path = cli.config.as_ref().map(PathBuf::from).or(config_path);
     let (config, root_path) = if let Some(config_path) = maybe_config_path {
         let config = parse_config_file(&config_path, env_config.cache_dir)
             .with_context(|| format!("failed to parse config file from {:?}", config_path))?;
+        let root_path = config_path
+            .parent()
+            .map(|p| p.to_owned())
+            .expect("Could not get root folder of cache");
+        config.validate(&root_path)?;
         debug!("Using configuration {:?}", &config);
-        (
-            config,
-            config_path
-                .parent()
-                .map(|p| p.to_owned())
-                .expect("Could not get root folder of cache"),
-        )
+        (config, root_path)
     } else {
         let config = Config::default();
         let dir = env::current_dir()?;
@@ -65,12 +79,23 @@ async fn initialize(cli: &CommandLineArgs) -> anyhow::Result<(Config, PathBuf, P
 
     Ok((config, root_path, cache_folder_path))
 }
+/// The project and task a command runs for, both are recorded in the cached command.
+#[derive(Clone, Copy)]
+struct Selection<'a> {
+    project: Option<&'a Project>,
+    task: Option<&'a str>,
+}
+
 async fn handle_existing_command(
     command_hash: Hash,
     command_string: &str,
-    cache_folder: CacheFolder,
+    cache_folder: &CacheFolder,
+    config: &Config,
 ) -> anyhow::Result<()> {
-    let command = cache_folder.get_cashed_command(&command_hash)?;
+    let mut command = cache_folder.get_cashed_command(&command_hash)?;
+    if let Some(signing) = &config.signing {
+        signing.verify(&command)?;
+    }
     info!(
         "Cache hit for {} [{}]",
         command_hash.to_hex(),
@@ -83,7 +108,7 @@ async fn handle_existing_command(
     let stdout_future = cached_output.restore_to_stdout();
 
     let mut set = JoinSet::new();
-    for output_file in command.output_files {
+    for output_file in command.output_files.clone() {
         let file_hash = Hash::from_hex(output_file.hash.as_bytes())?;
         let file = cache_folder.get_cached_file(&file_hash).await?;
         let paths = output_file
@@ -99,107 +124,279 @@ async fn handle_existing_command(
     }
     stdout_future.await?;
 
+    let mut restored = 0;
+    let mut skipped = 0;
     while let Some(res) = set.join_next().await {
         match res {
             Err(e) => bail!(e),
             Ok(task_result) => match task_result {
-                Err(e) => bail!(e),
-                Ok(file_name) => debug!("Restored files {:?}", file_name),
+                Err(e) => return Err(e),
+                Ok(restored_file) => {
+                    debug!(
+                        "Restored files {:?}, already up to date {:?}",
+                        restored_file.restored, restored_file.skipped
+                    );
+                    restored += restored_file.restored.len();
+                    skipped += restored_file.skipped.len();
+                }
             },
         }
     }
+    info!(
+        "Restored {} output files, skipped {} already up to date",
+        restored, skipped
+    );
+    // `export --since` selects commands by their last use
+    command.last_accessed = Utc::now();
+    if let Err(e) = command.store_in_cache(&cache_folder.root).await {
+        warn!("Could not update last access of {}: {:#}", command_hash, e);
+    }
     Ok(())
 }
 async fn handle_new_command(
     command_hash: Hash,
-    command_string: &str,
+    argv: &[String],
     cache_folder: CacheFolder,
     config: &Config,
-    project: Option<&Project>,
+    selection: Selection<'_>,
     root_folder: PathBuf,
     filtered_env: BTreeMap<String, String>,
 ) -> anyhow::Result<()> {
+    let command_string = config.exec.command_line(argv)?;
     info!(
         "Cash miss for {} [{}]",
         command_hash.to_hex(),
         command_string
     );
-    let temp_file_path = std::env::temp_dir().join(format!("{}.txt", command_hash.to_string()));
+    let temp_file_path = std::env::temp_dir().join(format!("{}.txt", command_hash));
 
-    let shell_command = config.exec.as_ref();
-    let mut process = tokio::process::Command::new(shell_command);
-    process.arg("-c");
-    process.arg(&command_string);
+    let Selection { project, task } = selection;
+    let mut process = config.exec.command(argv)?;
+    // commands without a project keep running where the cli was invoked
+    if let Some(project) = project {
+        process.current_dir(root_folder.join(project.workdir()));
+        process.envs(project.env.iter().map(|(k, v)| (k.as_str(), v.as_str())));
+    }
     process.stdout(Stdio::piped());
 
-    let mut child = process.spawn()?;
+    let mut child = process
+        .spawn()
+        .with_context(|| format!("Could not start {}", config.exec.program(argv)))?;
     let child_output = child.stdout.take();
-    if let Some(child_stdout) = child_output {
+    let redirect = if let Some(child_stdout) = child_output {
         tokio::spawn(redirect_to_file_and_stdout(
             temp_file_path.clone(),
             child_stdout,
-        ));
+        ))
     } else {
         bail!("Could not capture command output")
+    };
+    let status = child.wait().await?;
+    // the log is hashed next, it has to be written completely
+    redirect.await??;
+    if !status.success() {
+        // a failed run is neither cached nor restored later
+        _ = tokio::fs::remove_file(&temp_file_path).await;
+        bail!("Command [{}] failed with {}", command_string, status);
     }
-    child.wait().await?;
 
+    let mut policy = config.compression_policy(project, &root_folder);
+    if let Some(project) = project {
+        policy.dictionary = Dictionary::load(&cache_folder.root, &project.name)?;
+    }
+    let policy = Arc::new(policy);
     let (hash, size) = CachedFile::hash_path(&temp_file_path)?;
-    let command_line_output_hash =
-        CachedFile::create(cache_folder.root.clone(), temp_file_path, hash, size).await?;
+    let command_line_output_hash = CachedFile::create(
+        cache_folder.root.clone(),
+        temp_file_path,
+        hash,
+        size,
+        policy.clone(),
+    )
+    .await?;
 
     let output_files = if let Some(project) = project {
         project
-            .gather_output_files(&root_folder, &cache_folder)
+            .gather_output_files(&root_folder, &cache_folder, policy)
             .await?
     } else {
         vec![]
     };
-    let cached_command = CachedCommand {
+    let mut cached_command = CachedCommand {
+        version: FORMAT_VERSION,
         command_line: command_string.into(),
         created: Utc::now(),
-        env: filtered_env.into(),
+        env: filtered_env,
         hash: command_hash.to_string().into(),
         last_accessed: Utc::now(),
         log: command_line_output_hash.to_string().into(),
         output_files,
+        project: project.map(|p| p.name.clone()),
+        task: task.map(|t| t.into()),
+        signature: None,
     };
+    if let Some(signing) = &config.signing
+        && let Some(key) = signing.signing_key(&root_folder)?
+    {
+        cached_command.signature = Some(sign(&key, &cached_command));
+    }
     cached_command.store_in_cache(&cache_folder.root).await?;
     Ok(())
 }
 
-async fn handle_command(
-    command_string: &str,
-    all_input_paths: Vec<PathBuf>,
+/// Computes the inputs of the project (or task) and restores or runs the command.
+async fn run_cached(
+    argv: &[String],
+    selection: Selection<'_>,
     root_folder: PathBuf,
     cache_folder_path: PathBuf,
     config: &Config,
-    project: Option<&Project>,
 ) -> anyhow::Result<()> {
+    if let Some(project) = selection.project {
+        info!("Operating in project {}", project.name);
+    }
+    let cache_folder = CacheFolder::new(cache_folder_path);
     let env_vars = env::vars().collect::<BTreeMap<String, String>>();
-    let filtered_env = config.filter_env_vars(&env_vars, &root_folder)?;
+    let command_string = &config.exec.command_line(argv)?;
+    let (command_hash, filtered_env) = config.command_hash(
+        command_string,
+        selection.project,
+        selection.task,
+        &root_folder,
+        &cache_folder,
+        &env_vars,
+    )?;
     debug!("Filtered env: {:?}", &filtered_env);
-
-    let command_hash = CachedCommand::create_hash(&command_string, all_input_paths, &filtered_env)?;
     debug!(
         "Computed command hash {} for '{}'",
         command_hash.to_string(),
         command_string
     );
-    let cache_folder = CacheFolder::new(cache_folder_path);
     if cache_folder.has_cached_command(&command_hash) {
-        handle_existing_command(command_hash, command_string, cache_folder).await?;
-    } else {
-        handle_new_command(
-            command_hash,
-            command_string,
-            cache_folder,
-            config,
-            project,
-            root_folder,
-            filtered_env,
-        )
-        .await?;
+        let result =
+            handle_existing_command(command_hash, command_string, &cache_folder, config).await;
+        let Err(e) = result else {
+            return Ok(());
+        };
+        match e.downcast_ref::<CacheError>() {
+            Some(CacheError::CorruptBlob { hash, reason }) => {
+                warn!(
+                    "Cached file {} is corrupt ({}), discarding it and running the command",
+                    hash, reason
+                );
+                cache_folder.discard_corrupt(&command_hash, &Hash::from_hex(hash.as_bytes())?)?;
+            }
+            Some(CacheError::UntrustedCommand { .. }) => {
+                warn!("Ignoring cached command: {}", e);
+            }
+            Some(CacheError::MissingKey { .. }) => {
+                warn!("Treating {} as cache miss: {}", command_hash, e);
+            }
+            Some(CacheError::UnknownVersion { .. }) => {
+                info!("Treating {} as cache miss: {}", command_hash, e);
+            }
+            _ => return Err(e),
+        }
+    }
+    handle_new_command(
+        command_hash,
+        argv,
+        cache_folder,
+        config,
+        selection,
+        root_folder,
+        filtered_env,
+    )
+    .await
+}
+
+async fn train_dictionary(
+    project: &Project,
+    root_folder: &Path,
+    cache_folder_path: &Path,
+    size: usize,
+    samples: usize,
+) -> anyhow::Result<()> {
+    let paths = get_paths_from_globs(&project.outputs, root_folder);
+    let (data, samples) =
+        tokio::task::spawn_blocking(move || Dictionary::train(&paths, samples, size)).await??;
+    let dictionary = Dictionary::store(cache_folder_path, &project.name, data, samples).await?;
+    info!(
+        "Stored dictionary {} ({} bytes) for project {}",
+        dictionary.hash,
+        dictionary.data.len(),
+        project.name
+    );
+    Ok(())
+}
+
+/// Runs the task of every pending project once all of its pending dependencies succeeded,
+/// at most `jobs` at a time. After a failure no new tasks are started.
+async fn run_graph(
+    config: Arc<Config>,
+    task: SmolStr,
+    mut pending: BTreeMap<SmolStr, BTreeSet<SmolStr>>,
+    jobs: usize,
+    root_path: PathBuf,
+    cache_folder_path: PathBuf,
+) -> anyhow::Result<()> {
+    let mut running = JoinSet::<anyhow::Result<SmolStr>>::new();
+    let mut failed = None;
+    loop {
+        if failed.is_none() {
+            let ready = pending
+                .iter()
+                .filter(|(_, dependencies)| dependencies.is_empty())
+                .map(|(name, _)| name.clone())
+                .take(jobs.max(1).saturating_sub(running.len()))
+                .collect::<Vec<_>>();
+            for name in ready {
+                pending.remove(&name);
+                let config = config.clone();
+                let task = task.clone();
+                let root_path = root_path.clone();
+                let cache_folder_path = cache_folder_path.clone();
+                running.spawn(async move {
+                    let target = format!("{}:{}", name, task);
+                    let (project, _, definition) = config.resolve_task(&target, None)?;
+                    info!("Running {}", target);
+                    let selection = Selection {
+                        project: Some(&project),
+                        task: Some(&task),
+                    };
+                    run_cached(
+                        &config.exec.task_argv(&definition.command)?,
+                        selection,
+                        root_path,
+                        cache_folder_path,
+                        &config,
+                    )
+                    .await
+                    .with_context(|| format!("Task {} failed", target))?;
+                    Ok(name)
+                });
+            }
+        }
+        let Some(result) = running.join_next().await else {
+            break;
+        };
+        match result? {
+            Ok(name) => pending.values_mut().for_each(|dependencies| {
+                dependencies.remove(&name);
+            }),
+            Err(e) => {
+                failed.get_or_insert(e);
+            }
+        }
+    }
+    if let Some(e) = failed {
+        return Err(e);
+    }
+    if !pending.is_empty() {
+        bail!(
+            "Could not run {} because of a dependency cycle",
+            pending.keys().join(", ")
+        );
     }
     Ok(())
 }
@@ -220,38 +417,219 @@ async fn main() -> anyhow::Result<()> {
             .expect("Could not change log level to DEBUG");
     }
 
+    // the schema helps writing a config, so it must not need a valid one
+    if let Some(Action::Schema { project_file }) = cli.action {
+        let schema = if project_file {
+            schema_for!(Project)
+        } else {
+            schema_for!(Config)
+        };
+        println!("{}", serde_json::to_string_pretty(&schema)?);
+        return Ok(());
+    }
+
     let (config, root_path, cache_folder_path) = initialize(&cli).await?;
+    if let Some(encryption) = &config.encryption {
+        encryption.install_key(&root_path)?;
+    }
     if cli.clear {
         info!("Clearing cache folder {:?}", &cache_folder_path);
         fs::remove_dir_all(&cache_folder_path)?;
     }
 
     let working_dir_project = config.get_project_for_cwd(&root_path)?;
-    let cli_project = cli.project.map(|name| config.get_project(&name)).flatten();
+    let cli_project = cli
+        .project
+        .as_deref()
+        .map(|name| config.require_project(name))
+        .transpose()?;
     let project = cli_project.or(working_dir_project);
-    let inputs = if let Some(project) = project {
-        info!("Operating in project {}", project.name);
-        config.get_all_depenend_file_globs(&project)?
-    } else {
-        vec![]
-    };
-
-    let all_paths = get_paths_from_globs(&inputs, &root_path)
-        .into_iter()
-        .unique()
-        .collect();
+    if !matches!(cli.action, Some(Action::Migrate)) {
+        CacheFormat::check(&cache_folder_path)?;
+    }
 
-    let command_string = cli.command.join(" ");
-    if command_string.trim().is_empty() {
+    if let Some(action) = cli.action {
+        return match action {
+            Action::GenerateSigningKey { output } => {
+                let public_key = generate_key_file(&output)?;
+                info!(
+                    "Wrote signing key to {:?}, add its public key {} to trusted_keys",
+                    output, public_key
+                );
+                Ok(())
+            }
+            Action::Run {
+                target,
+                all,
+                with_deps,
+                jobs,
+            } => {
+                if all || with_deps {
+                    let (task, selected) = if all {
+                        let selected = config
+                            .projects
+                            .iter()
+                            .filter(|p| p.tasks.contains_key(target.as_str()))
+                            .map(|p| p.name.clone())
+                            .collect::<BTreeSet<_>>();
+                        if selected.is_empty() {
+                            bail!("No project defines the task \"{}\"", target);
+                        }
+                        (SmolStr::from(&target), selected)
+                    } else {
+                        let (project, name, _) = config.resolve_task(&target, project)?;
+                        let mut selected = dependency_closure(&config.projects, &project.name);
+                        selected.retain(|member| {
+                            config
+                                .get_project(member)
+                                .is_some_and(|p| p.tasks.contains_key(name.as_str()))
+                        });
+                        (name, selected)
+                    };
+                    let pending = selected_dependencies(&config.projects, &selected);
+                    return run_graph(
+                        Arc::new(config.clone()),
+                        task,
+                        pending,
+                        jobs,
+                        root_path,
+                        cache_folder_path,
+                    )
+                    .await;
+                }
+                let (project, name, task) = config.resolve_task(&target, project)?;
+                let selection = Selection {
+                    project: Some(&project),
+                    task: Some(&name),
+                };
+                run_cached(
+                    &config.exec.task_argv(&task.command)?,
+                    selection,
+                    root_path,
+                    cache_folder_path,
+                    &config,
+                )
+                .await
+            }
+            Action::Affected { base, run, jobs } => {
+                let changed = changed_files(&root_path, &cache_folder_path, &base)?;
+                let affected = affected_projects(&config, &changed)?;
+                info!(
+                    "{} changed files affect {} projects",
+                    changed.len(),
+                    affected.len()
+                );
+                let Some(task) = run else {
+                    affected.iter().for_each(|name| println!("{}", name));
+                    return Ok(());
+                };
+                let mut selected = affected;
+                selected.retain(|name| {
+                    config
+                        .get_project(name)
+                        .is_some_and(|p| p.tasks.contains_key(task.as_str()))
+                });
+                let pending = selected_dependencies(&config.projects, &selected);
+                run_graph(
+                    Arc::new(config.clone()),
+                    task.into(),
+                    pending,
+                    jobs,
+                    root_path,
+                    cache_folder_path,
+                )
+                .await
+            }
+            Action::Schema { .. } => unreachable!("printed before loading the config"),
+            Action::Migrate => {
+                let summary = migrate(&cache_folder_path)?;
+                info!(
+                    "Cache folder is at format version {} (was {}), rewrote {} files, {} commands and {} pack indexes",
+                    summary.to, summary.from, summary.files, summary.commands, summary.packs
+                );
+                Ok(())
+            }
+            Action::TrainDictionary { size, samples } => {
+                let project = project.ok_or(anyhow!(
+                    "Training a dictionary needs a project, select one with -p"
+                ))?;
+                train_dictionary(project, &root_path, &cache_folder_path, size, samples).await
+            }
+            Action::Repack {
+                max_blob_size,
+                max_pack_size,
+            } => {
+                let summary = repack(&cache_folder_path, max_blob_size, max_pack_size).await?;
+                info!(
+                    "Packed {} blobs ({} bytes) into {} packs",
+                    summary.blobs, summary.bytes, summary.packs
+                );
+                Ok(())
+            }
+            Action::Export {
+                since,
+                commands,
+                output,
+            } => {
+                let filter = ExportFilter {
+                    project: cli.project.map(|p| p.into()),
+                    commands: commands.into_iter().map(|c| c.into()).collect(),
+                    // a longer duration than representable keeps everything
+                    since: since.map(|since| {
+                        Utc::now()
+                            .checked_sub_signed(since)
+                            .unwrap_or(DateTime::<Utc>::MIN_UTC)
+                    }),
+                };
+                let summary = tokio::task::spawn_blocking(move || {
+                    export(&cache_folder_path, &filter, &output)
+                })
+                .await??;
+                info!(
+                    "Exported {} commands with {} files",
+                    summary.commands, summary.blobs
+                );
+                Ok(())
+            }
+            Action::Import { archive } => {
+                let summary = import(&cache_folder_path, &archive).await?;
+                info!(
+                    "Imported {} commands and {} files, kept {} newer cached commands",
+                    summary.commands, summary.blobs, summary.skipped_commands
+                );
+                Ok(())
+            }
+            Action::Verify { repair } => {
+                let report = verify(&cache_folder_path, repair).await?;
+                for problem in &report.problems {
+                    warn!("{}", problem);
+                }
+                if repair {
+                    info!("Removed {} broken entries", report.repaired);
+                } else if !report.problems.is_empty() {
+                    bail!(
+                        "Cache folder {:?} has {} broken entries, run verify --repair to remove them",
+                        cache_folder_path,
+                        report.problems.len()
+                    );
+                }
+                Ok(())
+            }
+        };
+    }
+    if cli.command.join(" ").trim().is_empty() {
         debug!("Empty command, don't process");
     } else {
-        handle_command(
-            &command_string,
-            all_paths,
+        // the arguments are passed on as given, only interpreters get them joined into one line
+        run_cached(
+            &cli.command,
+            Selection {
+                project,
+                task: None,
+            },
             root_path,
             cache_folder_path,
             &config,
-            project,
         )
         .await?;
     }
@@ -0,0 +1,265 @@
+use std::{
+    fs,
+    path::Path,
+    process::{Command, Output},
+};
+
+fn run(dir: &Path, args: &[&str]) -> Output {
+    run_with_env(dir, args, &[])
+}
+
+fn run_with_env(dir: &Path, args: &[&str], env: &[(&str, &str)]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_simple-ci-cache"))
+        .current_dir(dir)
+        .args(args)
+        .env_remove("CACHE_ENCRYPTION_KEY")
+        .env_remove("CACHE_SIGNING_KEY")
+        .env("RUST_BACKTRACE", "0")
+        .envs(env.iter().copied())
+        .output()
+        .expect("could not start simple-ci-cache")
+}
+
+/// A folder with `cache.yml` and the given project folders.
+fn workspace(config: &str, folders: &[&str]) -> tempfile::TempDir {
+    let dir = tempfile::tempdir().unwrap();
+    for folder in folders {
+        fs::create_dir_all(dir.path().join(folder)).unwrap();
+    }
+    fs::write(dir.path().join("cache.yml"), config).unwrap();
+    dir
+}
+
+fn commands(dir: &Path) -> usize {
+    fs::read_dir(dir.join(".cache/commands")).map_or(0, |entries| entries.count())
+}
+
+#[test]
+fn failing_dependency_stops_its_dependents() {
+    let dir = workspace(
+        r#"
+cache_dir: .cache
+ttl: 7
+projects:
+  - name: lib
+    root: lib
+    tasks:
+      build: echo building lib; exit 3
+  - name: app
+    root: app
+    depends_on: [lib]
+    tasks:
+      build: touch built
+"#,
+        &["lib", "app"],
+    );
+    for _ in 0..2 {
+        let output = run(dir.path(), &["run", "--all", "build"]);
+        assert!(!output.status.success());
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert!(stderr.contains("Task lib:build failed"), "{}", stderr);
+        assert!(!dir.path().join("app/built").exists());
+        assert_eq!(commands(dir.path()), 0, "a failed run must not be cached");
+    }
+}
+
+#[test]
+fn failing_command_is_not_cached() {
+    let dir = workspace("cache_dir: .cache\nttl: 7\n", &[]);
+    let output = run(dir.path(), &["--", "exit 3"]);
+    assert!(!output.status.success());
+    assert_eq!(commands(dir.path()), 0);
+}
+
+#[test]
+fn export_keeps_encrypted_records_sealed() {
+    let config = "cache_dir: .cache\nttl: 7\nencryption: {}\n";
+    let key = "11".repeat(32);
+    let dir = workspace(config, &[]);
+    let encrypted = |args: &[&str]| {
+        let output = run_with_env(dir.path(), args, &[("CACHE_ENCRYPTION_KEY", &key)]);
+        assert!(output.status.success(), "{:?}", output);
+        output
+    };
+    encrypted(&["--", "echo secret-command-line"]);
+    encrypted(&["export", "-o", "export.tar"]);
+
+    let archive = fs::read(dir.path().join("export.tar")).unwrap();
+    let contains = |needle: &str| {
+        archive
+            .windows(needle.len())
+            .any(|w| w == needle.as_bytes())
+    };
+    assert!(contains("\"sealed\""));
+    assert!(!contains("secret-command-line"));
+
+    // the sealed records are imported with the same key
+    fs::remove_dir_all(dir.path().join(".cache")).unwrap();
+    encrypted(&["import", "export.tar"]);
+    let output = encrypted(&["--", "echo secret-command-line"]);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("Cache hit"), "{}", stdout);
+}
+
+#[test]
+fn cache_hits_count_as_access_for_export_since() {
+    let dir = workspace("cache_dir: .cache\nttl: 7\n", &[]);
+    assert!(run(dir.path(), &["--", "echo hello"]).status.success());
+    let record = fs::read_dir(dir.path().join(".cache/commands"))
+        .unwrap()
+        .next()
+        .unwrap()
+        .unwrap()
+        .path()
+        .join("command.json");
+    let mut command: serde_json::Value =
+        serde_json::from_slice(&fs::read(&record).unwrap()).unwrap();
+    command["last_accessed"] = "2000-01-01T00:00:00Z".into();
+    fs::write(&record, command.to_string()).unwrap();
+
+    let exported = |dir: &Path| {
+        let output = run(dir, &["export", "--since", "1d", "-o", "export.tar"]);
+        String::from_utf8_lossy(&output.stdout).into_owned()
+    };
+    assert!(exported(dir.path()).contains("Exported 0 commands"));
+    assert!(run(dir.path(), &["--", "echo hello"]).status.success());
+    assert!(exported(dir.path()).contains("Exported 1 commands"));
+}
+
+#[test]
+fn dependency_outputs_decide_hits_of_dependents() {
+    let dir = workspace(
+        r#"
+cache_dir: .cache
+ttl: 7
+projects:
+  - name: lib
+    root: lib
+    inputs: [lib/src.txt]
+    outputs: [lib/out.txt]
+  - name: main
+    root: main
+    depends_on: [lib]
+    dependency_inputs: outputs
+"#,
+        &["lib", "main"],
+    );
+    let lib = |source: &str| {
+        fs::write(dir.path().join("lib/src.txt"), source).unwrap();
+        let output = run(
+            dir.path(),
+            &["-p", "lib", "--", "head -c 1 src.txt > out.txt"],
+        );
+        assert!(output.status.success(), "{:?}", output);
+    };
+    let main_hits = || {
+        let output = run(dir.path(), &["-p", "main", "--", "cat ../lib/out.txt"]);
+        assert!(output.status.success(), "{:?}", output);
+        String::from_utf8_lossy(&output.stdout).contains("Cache hit")
+    };
+
+    lib("a1");
+    assert!(!main_hits());
+    assert!(main_hits());
+    // lib runs again, but its output stays the same
+    lib("a2");
+    assert!(main_hits());
+    lib("b1");
+    assert!(!main_hits());
+}
+
+#[test]
+fn unknown_project_is_an_error() {
+    let dir = workspace(
+        "cache_dir: .cache\nttl: 7\nprojects:\n  - {name: api, root: api}\n",
+        &["api"],
+    );
+    let output = run(dir.path(), &["-p", "apj", "--", "touch ran"]);
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("did you mean \"api\"?"), "{}", stderr);
+    assert!(!dir.path().join("ran").exists());
+}
+
+#[test]
+fn corrupt_blob_is_discarded_and_the_command_rerun() {
+    let dir = workspace(
+        "cache_dir: .cache\nttl: 7\nprojects:\n  - {name: app, root: app, outputs: [app/out.txt]}\n",
+        &["app"],
+    );
+    let build = || {
+        let output = run(
+            dir.path(),
+            &[
+                "-p",
+                "app",
+                "--",
+                "echo run >> runs.txt; echo content > out.txt",
+            ],
+        );
+        assert!(output.status.success(), "{:?}", output);
+        String::from_utf8_lossy(&output.stdout).into_owned()
+    };
+    let runs = || {
+        fs::read_to_string(dir.path().join("app/runs.txt"))
+            .unwrap()
+            .lines()
+            .count()
+    };
+
+    build();
+    let blob = dir
+        .path()
+        .join(".cache/files")
+        .join(blake3::hash(b"content\n").to_hex().as_str())
+        .join("compressed");
+    let mut stored = fs::read(&blob).unwrap();
+    stored[0] ^= 1;
+    fs::write(&blob, stored).unwrap();
+    fs::remove_file(dir.path().join("app/out.txt")).unwrap();
+
+    let stdout = build();
+    assert!(stdout.contains("is corrupt"), "{}", stdout);
+    assert_eq!(runs(), 2);
+    assert_eq!(
+        fs::read_to_string(dir.path().join("app/out.txt")).unwrap(),
+        "content\n"
+    );
+    assert!(build().contains("Cache hit"));
+    assert_eq!(runs(), 2);
+}
+
+#[test]
+fn nested_roots_pick_the_deepest_project() {
+    let dir = workspace(
+        r#"
+cache_dir: .cache
+ttl: 7
+projects:
+  - name: repo
+    root: .
+  - name: api
+    root: svc/api
+    envs: [API_TOKEN]
+"#,
+        &["svc/api/src"],
+    );
+    let output = run(&dir.path().join("svc/api/src"), &["--", "touch marker"]);
+    assert!(output.status.success(), "{:?}", output);
+    assert!(dir.path().join("svc/api/marker").exists());
+    assert!(!dir.path().join("marker").exists());
+
+    // the env vars of the selected project are hashed, not those of the cwd's project
+    let hits = |token: &str| {
+        let output = run_with_env(
+            dir.path(),
+            &["-p", "api", "--", "true"],
+            &[("API_TOKEN", token)],
+        );
+        assert!(output.status.success(), "{:?}", output);
+        String::from_utf8_lossy(&output.stdout).contains("Cache hit")
+    };
+    assert!(!hits("one"));
+    assert!(hits("one"));
+    assert!(!hits("two"));
+}